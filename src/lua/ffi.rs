@@ -1,4 +1,4 @@
-use std::libc::{c_void, size_t, c_int, c_char, c_double};
+use std::libc::{c_void, size_t, c_int, c_char, c_double, ptrdiff_t};
 
 // Option for multiple returns in `lua_pcall' and `lua_call'
 pub static LUA_MULTRET: c_int = -1;
@@ -10,6 +10,10 @@ pub static LUA_ERRSYNTAX: c_int = 3;
 pub static LUA_ERRMEM:    c_int = 4;
 pub static LUA_ERRERR:    c_int = 5;
 
+// Special references returned by `luaL_ref'
+pub static LUA_NOREF:  c_int = -2;
+pub static LUA_REFNIL: c_int = -1;
+
 // Pseudo-indices
 pub static LUA_REGISTRYINDEX: c_int = -10000;
 pub static LUA_ENVIRONINDEX:  c_int = -10001;
@@ -27,53 +31,126 @@ pub static LUA_TFUNCTION:      c_int = 6;
 pub static LUA_TUSERDATA:      c_int = 7;
 pub static LUA_TTHREAD:        c_int = 8;
 
+// Event masks for `lua_sethook'
+pub static LUA_MASKCALL:  c_int = 1 << 0;
+pub static LUA_MASKRET:   c_int = 1 << 1;
+pub static LUA_MASKLINE:  c_int = 1 << 2;
+pub static LUA_MASKCOUNT: c_int = 1 << 3;
+
+// Modes for `luaJIT_setmode'
+pub static LUAJIT_MODE_ENGINE: c_int = 0;
+pub static LUAJIT_MODE_OFF:    c_int = 0x0000;
+pub static LUAJIT_MODE_ON:     c_int = 0x0100;
+
+pub static LUA_IDSIZE: uint = 60;
+
 pub type lua_State = c_void;
 pub type lua_Number = c_double;
+pub type lua_Integer = ptrdiff_t;
 pub type LuaCallback = extern "C" fn(*lua_State) -> c_int;
+pub type lua_Hook = extern "C" fn(L: *lua_State, ar: *lua_Debug);
+pub type lua_Alloc = extern "C" fn(ud: *c_void, ptr: *c_void, osize: size_t, nsize: size_t) -> *c_void;
+
+pub struct lua_Debug {
+	event: c_int,
+	name: *c_char,
+	namewhat: *c_char,
+	what: *c_char,
+	source: *c_char,
+	currentline: c_int,
+	nups: c_int,
+	linedefined: c_int,
+	lastlinedefined: c_int,
+	short_src: [c_char, ..LUA_IDSIZE],
+	i_ci: c_int
+}
 
 #[link_args = "-lluajit-5.1"]
 extern {
 	fn luaL_newstate() -> *lua_State;
+	fn lua_newstate(f: lua_Alloc, ud: *c_void) -> *lua_State;
+	fn lua_getallocf(L: *lua_State, ud: *mut *c_void) -> lua_Alloc;
 	fn lua_close(L: *lua_State);
+	fn lua_newthread(L: *lua_State) -> *lua_State;
+	fn lua_resume(L: *lua_State, narg: c_int) -> c_int;
+	fn lua_yield(L: *lua_State, nresults: c_int) -> c_int;
+	fn lua_status(L: *lua_State) -> c_int;
+	fn lua_xmove(from: *lua_State, to: *lua_State, n: c_int);
 
 	fn luaL_openlibs(L: *lua_State);
+	fn luaopen_base(L: *lua_State) -> c_int;
+	fn luaopen_package(L: *lua_State) -> c_int;
+	fn luaopen_string(L: *lua_State) -> c_int;
+	fn luaopen_table(L: *lua_State) -> c_int;
+	fn luaopen_math(L: *lua_State) -> c_int;
+	fn luaopen_io(L: *lua_State) -> c_int;
+	fn luaopen_os(L: *lua_State) -> c_int;
+	fn luaopen_debug(L: *lua_State) -> c_int;
+	fn luaopen_bit(L: *lua_State) -> c_int;
+	fn luaopen_jit(L: *lua_State) -> c_int;
+	fn luaopen_ffi(L: *lua_State) -> c_int;
+
 	fn luaL_loadfile(L: *lua_State, filename: *c_char) -> c_int;
 	fn luaL_loadstring(L: *lua_State, s: *c_char) -> c_int;
+	fn luaL_ref(L: *lua_State, t: c_int) -> c_int;
+	fn luaL_unref(L: *lua_State, t: c_int, reference: c_int);
+	fn luaL_newmetatable(L: *lua_State, tname: *c_char) -> c_int;
+	fn luaL_callmeta(L: *lua_State, obj: c_int, e: *c_char) -> c_int;
 
 	fn lua_getfield(L: *lua_State, index: c_int, name: *c_char);
+	fn lua_gettable(L: *lua_State, index: c_int);
+	fn lua_rawget(L: *lua_State, index: c_int);
 	fn lua_pcall(L: *lua_State, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int;
 	fn lua_type(L: *lua_State, index: c_int) -> c_int;
 	fn lua_gettop(L: *lua_State) -> c_int;
 
 	fn lua_insert(L: *lua_State, index: c_int);
+	fn lua_pushvalue(L: *lua_State, index: c_int);
 
 	fn lua_createtable(L: *lua_State, narr: c_int, nrec: c_int);
 	fn lua_newtable(L: *lua_State);
 	fn lua_settable(L: *lua_State, index: c_int);
 	fn lua_next(L: *lua_State, index: c_int) -> c_int;
+	fn lua_rawgeti(L: *lua_State, index: c_int, n: c_int);
+
+	fn lua_newuserdata(L: *lua_State, size: size_t) -> *c_void;
+	fn lua_getmetatable(L: *lua_State, index: c_int) -> c_int;
+	fn lua_setmetatable(L: *lua_State, index: c_int) -> c_int;
+	fn lua_rawequal(L: *lua_State, index1: c_int, index2: c_int) -> c_int;
+	fn lua_setfenv(L: *lua_State, index: c_int) -> c_int;
+	fn lua_error(L: *lua_State) -> c_int;
+	fn lua_sethook(L: *lua_State, f: lua_Hook, mask: c_int, count: c_int) -> c_int;
+	fn lua_getstack(L: *lua_State, level: c_int, ar: *mut lua_Debug) -> c_int;
+	fn lua_getinfo(L: *lua_State, what: *c_char, ar: *mut lua_Debug) -> c_int;
+	fn luaJIT_setmode(L: *lua_State, index: c_int, mode: c_int) -> c_int;
 
 	fn lua_setfield(L: *lua_State, index: c_int, name: *c_char);
 	fn lua_rawset(L: *lua_State, index: c_int);
 	fn lua_rawseti(L: *lua_State, index: c_int, n: c_int);
 
 	fn lua_pushboolean(L: *lua_State, boolean: c_int);
-	fn lua_pushinteger(L: *lua_State, integer: c_int);
+	fn lua_pushinteger(L: *lua_State, integer: lua_Integer);
 	fn lua_pushnumber(L: *lua_State, number: lua_Number);
 	fn lua_pushlstring(L: *lua_State, string: *c_char, len: size_t);
 	fn lua_pushstring(L: *lua_State, string: *c_char);
 	fn lua_pushcclosure(L: *lua_State, cb: LuaCallback, upvals: c_int);
 	fn lua_pushnil(L: *lua_State);
+	fn lua_pushlightuserdata(L: *lua_State, p: *c_void);
 
 	fn lua_isfunction(L: *lua_State, index: c_int) -> c_int;
 	fn lua_isnumber(L: *lua_State, index: c_int) -> c_int;
 	fn lua_isstring(L: *lua_State, index: c_int) -> c_int;
 	fn lua_isnil(L: *lua_State, index: c_int) -> c_int;
 
+	fn lua_objlen(L: *lua_State, index: c_int) -> size_t;
 	fn lua_remove(L: *lua_State, index: c_int);
 	fn lua_settop(L: *lua_State, index: c_int);
 
 	fn lua_toboolean(L: *lua_State, index: c_int) -> c_int;
-	fn lua_tointeger(L: *lua_State, index: c_int) -> c_int;
+	fn lua_tointeger(L: *lua_State, index: c_int) -> lua_Integer;
 	fn lua_tonumber(L: *lua_State, index: c_int) -> lua_Number;
 	fn lua_tolstring(L: *lua_State, index: c_int, len: *size_t) -> *c_char;
+	fn lua_touserdata(L: *lua_State, index: c_int) -> *c_void;
+	fn lua_tothread(L: *lua_State, index: c_int) -> *lua_State;
+	fn lua_topointer(L: *lua_State, index: c_int) -> *c_void;
 }
@@ -1,6 +1,16 @@
+extern mod extra;
 pub use self::state::*;
 pub use ffi::LuaCallback;
-use std::hashmap::HashMap;
+use extra::container::Deque;
+use extra::ringbuf::RingBuf;
+use extra::treemap::{TreeMap, TreeSet};
+use std::hashmap::{HashMap, HashSet};
+use std::libc::{c_int, c_void};
+use std::unstable::intrinsics;
+use std::{cast, f32, ptr, str, sys, vec};
+use std::num::Bounded;
+use std::rt::local::Local;
+use std::rt::task::Task;
 mod state;
 
 struct Lua {
@@ -13,6 +23,14 @@ pub fn New() -> Lua {
 	}
 }
 
+/// Create a Lua state capped at limit bytes of memory. See `NewLimitedState()`.
+pub fn NewLimited(limit: uint) -> Option<Lua> {
+	match state::NewLimitedState(limit) {
+		Some(state) => Some(Lua{ state: state }),
+		None => None
+	}
+}
+
 impl Lua {
 	///Push a value to the Lua stack.
 	pub fn push<T: LuaPush>(&self, p: T) {
@@ -28,14 +46,34 @@ impl Lua {
 		LuaTo::lua_to(&self.state, index)
 	}
 
+	/// Get a value from a Lua stack index, or an error if it's the wrong type.
+	pub fn try_i_to<T: LuaTo>(&self, index: int) -> Result<T, LuaErr> {
+		LuaTo::try_lua_to(&self.state, index)
+	}
+
 	/**
 	 * Pop a top value from the stack and returns it.
 	 *
+	 * Values that span several slots, such as tuples, are read from the
+	 * topmost slots and all of them are popped.
 	 * Fails if the value in the top index is the wrong type.
 	 */
 	pub fn pop<T: LuaTo>(&self) -> T {
-		let v: T = self.i_to(-1);
-		self.state.pop(1);
+		let n = LuaTo::lua_size(None::<T>);
+		let v: T = self.i_to(-n);
+		self.state.pop(n);
+		return v;
+	}
+
+	/**
+	 * Pop a top value from the stack and return it.
+	 *
+	 * The value is popped even if it couldn't be converted.
+	 */
+	pub fn try_pop<T: LuaTo>(&self) -> Result<T, LuaErr> {
+		let n = LuaTo::lua_size(None::<T>);
+		let v: Result<T, LuaErr> = self.try_i_to(-n);
+		self.state.pop(n);
 		return v;
 	}
 	
@@ -45,11 +83,18 @@ impl Lua {
 	 * Fails if the value in index is not a table.
 	 */
 	pub fn table_iter<'a, K: LuaTo, V: LuaTo>(&'a self, index: int) -> LuaTableIterator<'a, K, V> {
+		match self.try_table_iter(index) {
+			Ok(iter) => iter,
+			Err(_) => { fail!(fmt!("Lua.table_iter() failed, value at index %d is not a table", index)) }
+		}
+	}
+
+	/// Get a (Key, Value) iterator for the table at stack index, or an error if it's not a table.
+	pub fn try_table_iter<'a, K: LuaTo, V: LuaTo>(&'a self, index: int) -> Result<LuaTableIterator<'a, K, V>, LuaErr> {
 		match self.state.index_type(index) {
-			state::TTable => {},
-			_ => { fail!(fmt!("Lua.table_iter() failed, value at index %d is not a table", index)) }
-		};
-		LuaTableIterator{ lua: self, index: index, started: false, closed: false }
+			state::TTable => Ok(LuaTableIterator{ lua: self, index: self.state.abs_index(index), started: false, closed: false }),
+			t => Err(self.state.type_err("table", t))
+		}
 	}
 
 	/**
@@ -58,11 +103,154 @@ impl Lua {
 	 * Fails if the value in index is not a table.
 	 */
 	pub fn arr_iter<'a, T: LuaTo>(&'a self, index: int) -> LuaArrayIterator<'a, T> {
+		match self.try_arr_iter(index) {
+			Ok(iter) => iter,
+			Err(_) => { fail!(fmt!("Lua.arr_iter() failed, value at index %d is not a table", index)) }
+		}
+	}
+
+	/// Get a value iterator for the table at stack index, or an error if it's not a table.
+	pub fn try_arr_iter<'a, T: LuaTo>(&'a self, index: int) -> Result<LuaArrayIterator<'a, T>, LuaErr> {
 		match self.state.index_type(index) {
-			state::TTable => {},
-			_ => { fail!(fmt!("Lua.arr_iter() failed, value at index %d is not a table", index)) }
-		};
-		LuaArrayIterator{ lua: self, index: index, started: false, closed: false }
+			state::TTable => Ok(LuaArrayIterator{ lua: self, index: self.state.abs_index(index), started: false, closed: false }),
+			t => Err(self.state.type_err("table", t))
+		}
+	}
+
+	/**
+	 * Get the value of a global variable.
+	 *
+	 * Fails if the global is the wrong type.
+	 */
+	pub fn global<T: LuaTo>(&self, name: &str) -> T {
+		match self.try_global(name) {
+			Ok(v) => v,
+			Err(err) => fail!(fmt!("Lua.global() failed: %s", err.to_str()))
+		}
+	}
+
+	/**
+	 * Get the value of a global variable, or an error if it's the wrong type
+	 * or a metamethod of the globals table raises one.
+	 */
+	pub fn try_global<T: LuaTo>(&self, name: &str) -> Result<T, LuaErr> {
+		self.state.push_str(name);
+		match self.state.try_get_table(state::LUA_GLOBALSINDEX as int) {
+			Ok(()) => self.try_pop(),
+			Err(err) => Err(err)
+		}
+	}
+
+	/// Move a value into Lua as full userdata and push it.
+	pub fn push_userdata<T: LuaUserData>(&self, val: T) {
+		push_userdata(&self.state, val);
+	}
+
+	/**
+	 * Borrow the userdata of type T at a stack index, if it is one and isn't
+	 * already borrowed.
+	 */
+	pub fn userdata<'a, T: LuaUserData>(&'a self, index: int) -> Option<UserDataRef<'a, T>> {
+		get_userdata(&self.state, index)
+	}
+
+	/**
+	 * Push a Rust closure as a Lua function.
+	 *
+	 * The closure reads its arguments from the stack, pushes its results and
+	 * returns how many it pushed, or an error message to raise in Lua. It runs
+	 * like the body of a `lua_cb!` callback, see `run_callback()`. It's kept
+	 * in a userdata upvalue and dropped when Lua collects the function.
+	 */
+	pub fn push_fn(&self, f: ~fn(&state::State) -> Result<int, ~str>) {
+		push_fn(&self.state, f);
+	}
+
+	/// Create a coroutine that runs f when first resumed.
+	pub fn new_thread(&self, f: &LuaFunction) -> LuaThread {
+		let co = self.state.new_thread();
+		let thread = LuaRef::new(&self.state, -1);
+		self.state.pop(1);
+		f.lua_push(&co);
+		LuaThread{ thread: thread, co: co.raw() }
+	}
+
+	/**
+	 * Create a sandbox exposing only the listed globals.
+	 *
+	 * A name is either a global such as `"print"` or `"string"`, or a single
+	 * library function such as `"os.time"`. Library tables are copied and made
+	 * read-only, so sandboxed code can't change them for anyone else. Names that
+	 * aren't defined are skipped.
+	 *
+	 * Names that would let sandboxed code reach the real globals, write to
+	 * shared tables or call native code fail: `rawset`, `getmetatable` (the
+	 * string metatable is shared), `getfenv`, `setfenv`, `load`, `loadstring`,
+	 * `loadfile`, `dofile`, `require`, `module` and anything in `debug`,
+	 * `package` (`package.loaded` holds the real libraries) or `ffi`.
+	 */
+	pub fn new_sandbox(&self, globals: &[&str]) -> Sandbox {
+		self.state.push_value(state::LUA_GLOBALSINDEX as int);
+		let g: LuaTable = self.pop();
+		let base = self.new_table();
+		let mut libs: ~[~str] = ~[];
+
+		for name in globals.iter() {
+			let path: ~[&str] = name.split_iter('.').collect();
+			if SANDBOX_UNSAFE.iter().any(|n| *n == path[0]) {
+				fail!(fmt!("Lua.new_sandbox() failed, %s can't be sandboxed", *name));
+			}
+			if path.len() == 1 {
+				match g.try_raw_get::<&str, LuaTable>(path[0]) {
+					Ok(lib) => {
+						let copy = self.sandbox_lib(&base, path[0], &mut libs);
+						for kv in lib.pairs::<LuaRef, LuaRef>() {
+							match kv {
+								(k, v) => { copy.raw_set(k, v); }
+							}
+						}
+					},
+					Err(_) => {
+						base.raw_set(path[0], g.raw_get::<&str, LuaRef>(path[0]));
+					}
+				}
+			} else if path.len() == 2 {
+				match g.try_raw_get::<&str, LuaTable>(path[0]) {
+					Ok(lib) => {
+						let copy = self.sandbox_lib(&base, path[0], &mut libs);
+						copy.raw_set(path[1], lib.raw_get::<&str, LuaRef>(path[1]));
+					},
+					Err(_) => {}
+				}
+			} else {
+				fail!(fmt!("Lua.new_sandbox() failed, invalid global name %s", *name));
+			}
+		}
+
+		for lib in libs.iter() {
+			let copy: LuaTable = base.raw_get(lib.as_slice());
+			base.raw_set(lib.as_slice(), read_only_table(&self.state, &copy));
+		}
+
+		Sandbox{ env: sandbox_env(&self.state, &base), base: base }
+	}
+
+	/// Get the sandbox copy of a library table, creating it if needed.
+	fn sandbox_lib(&self, base: &LuaTable, lib: &str, libs: &mut ~[~str]) -> LuaTable {
+		match base.try_raw_get::<&str, LuaTable>(lib) {
+			Ok(copy) => copy,
+			Err(_) => {
+				let copy = self.new_table();
+				base.raw_set(lib, copy.clone());
+				libs.push(lib.to_owned());
+				copy
+			}
+		}
+	}
+
+	/// Create a new empty table.
+	pub fn new_table(&self) -> LuaTable {
+		new_table(&self.state)
 	}
 
 	pub fn module<'l>(&'l self, mod_name: &str, def_fn: &fn(&LuaModule<'l>)) {
@@ -104,6 +292,13 @@ impl<'self> LuaModule<'self> {
 		self.lua.push(val);
 		self.lua.state().raw_set(self.table_i);
 	}
+
+	/// Define a Rust closure as a function in the module. See `Lua.push_fn()`.
+	pub fn def_fn(&self, name: &str, f: ~fn(&state::State) -> Result<int, ~str>) {
+		self.lua.push(name);
+		self.lua.push_fn(f);
+		self.lua.state().raw_set(self.table_i);
+	}
 }
 
 pub struct LuaArrayIterator<'self, V> {
@@ -122,7 +317,7 @@ impl<'self, T: LuaTo> Iterator<T> for LuaArrayIterator<'self, T> {
 			self.lua.state().push_nil();
 			self.started = true;
 		}
-		if !self.lua.state().next(self.index - 1) {
+		if !self.lua.state().next(self.index) {
 			self.closed = true;
 			return None;
 		}
@@ -148,7 +343,7 @@ impl<'self, K: LuaTo, V: LuaTo> Iterator<(K, V)> for LuaTableIterator<'self, K,
 			self.lua.state().push_nil();
 			self.started = true;
 		}
-		if !self.lua.state().next(self.index - 1) {
+		if !self.lua.state().next(self.index) {
 			self.closed = true;
 			return None;
 		}
@@ -160,10 +355,240 @@ impl<'self, K: LuaTo, V: LuaTo> Iterator<(K, V)> for LuaTableIterator<'self, K,
 
 pub trait LuaPush {
 	fn lua_push(&self, state: &state::State);
+
+	/// Like `lua_push`, but return an error message instead of pushing a value
+	/// Lua can't hold exactly. Values already pushed are left on the stack.
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		self.lua_push(state);
+		Ok(())
+	}
+
+	/// Push the value as the result of a Rust callback. Returns the number of
+	/// values pushed, or an error message to raise in Lua instead.
+	fn lua_return(&self, state: &state::State) -> Result<int, ~str> {
+		let top = state.get_top();
+		match self.try_lua_push(state) {
+			Ok(()) => Ok(state.get_top() - top),
+			Err(msg) => Err(msg)
+		}
+	}
+}
+
+/// Push a value, through `try_lua_push` if strict. Used by containers to check their contents.
+fn push_checked<T: LuaPush>(state: &state::State, v: &T, strict: bool) -> Result<(), ~str> {
+	if strict {
+		v.try_lua_push(state)
+	} else {
+		v.lua_push(state);
+		Ok(())
+	}
 }
 
 pub trait LuaTo {
-	fn lua_to(state: &state::State, index: int) -> Self;
+	/// Number of consecutive stack slots the value is read from.
+	fn lua_size(_: Option<Self>) -> int { 1 }
+
+	/// Convert the value at a stack index, or return an error if it has the wrong type.
+	fn try_lua_to(state: &state::State, index: int) -> Result<Self, LuaErr>;
+
+	/// Convert the value at a stack index, failing if it has the wrong type.
+	fn lua_to(state: &state::State, index: int) -> Self {
+		match LuaTo::try_lua_to(state, index) {
+			Ok(v) => v,
+			Err(err) => fail!(err.to_str())
+		}
+	}
+}
+
+impl LuaPush for () {
+	fn lua_push(&self, _: &state::State) {}
+}
+
+impl LuaTo for () {
+	fn lua_size(_: Option<()>) -> int { 0 }
+
+	fn try_lua_to(_: &state::State, _: int) -> Result<(), LuaErr> {
+		Ok(())
+	}
+}
+
+// Tuples are pushed as multiple values and read from consecutive stack slots.
+macro_rules! tuple_impls(
+	($($T:ident $v:ident),+) => (
+		impl<$($T: LuaPush),+> LuaPush for ($($T),+) {
+			fn lua_push(&self, state: &state::State) {
+				match *self {
+					($(ref $v),+) => { $( $v.lua_push(state); )+ }
+				}
+			}
+
+			fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+				match *self {
+					($(ref $v),+) => {
+						$(
+							match $v.try_lua_push(state) {
+								Ok(()) => {},
+								Err(msg) => { return Err(msg); }
+							}
+						)+
+					}
+				}
+				Ok(())
+			}
+		}
+
+		impl<$($T: LuaTo),+> LuaTo for ($($T),+) {
+			fn lua_size(_: Option<($($T),+)>) -> int {
+				0 $( + LuaTo::lua_size(None::<$T>) )+
+			}
+
+			fn try_lua_to(state: &state::State, index: int) -> Result<($($T),+), LuaErr> {
+				let mut _i = state.abs_index(index);
+				$(
+					let $v: $T = match LuaTo::try_lua_to(state, _i) {
+						Ok(v) => v,
+						Err(err) => { return Err(err); }
+					};
+					_i += LuaTo::lua_size(None::<$T>);
+				)+
+				Ok(($($v),+))
+			}
+		}
+	);
+)
+
+tuple_impls!(A a, B b)
+tuple_impls!(A a, B b, C c)
+tuple_impls!(A a, B b, C c, D d)
+tuple_impls!(A a, B b, C c, D d, E e)
+tuple_impls!(A a, B b, C c, D d, E e, F f)
+tuple_impls!(A a, B b, C c, D d, E e, F f, G g)
+tuple_impls!(A a, B b, C c, D d, E e, F f, G g, H h)
+tuple_impls!(A a, B b, C c, D d, E e, F f, G g, H h, I i)
+tuple_impls!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j)
+tuple_impls!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j, K k)
+tuple_impls!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j, K k, L l)
+
+/**
+ * Any number of values, pushed as separate stack values instead of a table.
+ *
+ * Return `Variadic(~[...])` from a callback to give Lua a variable number of results.
+ */
+pub struct Variadic<T>(~[T]);
+
+impl<T: LuaPush> LuaPush for Variadic<T> {
+	fn lua_push(&self, state: &state::State) {
+		let Variadic(ref vals) = *self;
+		for v in vals.iter() {
+			v.lua_push(state);
+		}
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		let Variadic(ref vals) = *self;
+		for v in vals.iter() {
+			match v.try_lua_push(state) {
+				Ok(()) => {},
+				Err(msg) => { return Err(msg); }
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Reads every value from the index to the top of the stack, for a callback's last argument.
+impl<T: LuaTo> LuaTo for Variadic<T> {
+	fn try_lua_to(state: &state::State, index: int) -> Result<Variadic<T>, LuaErr> {
+		let size = LuaTo::lua_size(None::<T>);
+		if size <= 0 {
+			// Nothing would be consumed, so the values couldn't be counted
+			return Err(state::runtime_err(~"can't read a variable number of values that take no stack slots"));
+		}
+
+		let mut vals = ~[];
+		let mut i = state.abs_index(index);
+		while i <= state.get_top() {
+			match LuaTo::try_lua_to(state, i) {
+				Ok(v) => vals.push(v),
+				Err(err) => { return Err(err); }
+			}
+			i += size;
+		}
+		Ok(Variadic(vals))
+	}
+}
+
+/// `None` is pushed as nil.
+impl<T: LuaPush> LuaPush for Option<T> {
+	fn lua_push(&self, state: &state::State) {
+		match *self {
+			Some(ref v) => v.lua_push(state),
+			None => state.push_nil()
+		}
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		match *self {
+			Some(ref v) => v.try_lua_push(state),
+			None => { state.push_nil(); Ok(()) }
+		}
+	}
+}
+
+/// nil, or a missing argument, converts to `None`.
+impl<T: LuaTo> LuaTo for Option<T> {
+	fn lua_size(_: Option<Option<T>>) -> int {
+		LuaTo::lua_size(None::<T>)
+	}
+
+	fn try_lua_to(state: &state::State, index: int) -> Result<Option<T>, LuaErr> {
+		match state.index_type(index) {
+			state::TNone | state::TNil => Ok(None),
+			_ => match LuaTo::try_lua_to(state, index) {
+				Ok(v) => Ok(Some(v)),
+				Err(err) => Err(err)
+			}
+		}
+	}
+}
+
+impl LuaPush for bool {
+	fn lua_push(&self, state: &state::State) {
+		state.push_bool(*self);
+	}
+}
+
+impl<'self> LuaPush for &'self bool {
+	fn lua_push(&self, state: &state::State) {
+		state.push_bool(**self);
+	}
+}
+
+impl LuaTo for bool {
+	fn try_lua_to(state: &state::State, index: int) -> Result<bool, LuaErr> {
+		state.try_to_bool(index)
+	}
+}
+
+/**
+ * A boolean read with Lua's truthiness rules instead of requiring a boolean.
+ *
+ * nil, false and missing arguments convert to `Truthy(false)`, anything else
+ * to `Truthy(true)`. Use it for flag arguments of callbacks.
+ */
+#[deriving(Eq, Clone)]
+pub struct Truthy(bool);
+
+impl LuaPush for Truthy {
+	fn lua_push(&self, state: &state::State) {
+		state.push_bool(**self);
+	}
+}
+
+impl LuaTo for Truthy {
+	fn try_lua_to(state: &state::State, index: int) -> Result<Truthy, LuaErr> {
+		Ok(Truthy(state.to_truthy(index)))
+	}
 }
 
 impl LuaPush for float {
@@ -179,26 +604,178 @@ impl<'self> LuaPush for &'self float {
 }
 
 impl LuaTo for float {
-	fn lua_to(state: &state::State, index: int) -> float {
-		return state.to_float(index);
+	fn try_lua_to(state: &state::State, index: int) -> Result<float, LuaErr> {
+		state.try_to_float(index)
+	}
+}
+
+// Lua numbers are doubles, so integers past 2^53 would be rounded
+static MAX_EXACT_INT: u64 = 1 << 53;
+
+fn exact_signed(n: i64, name: &str) -> Result<float, ~str> {
+	if n > MAX_EXACT_INT as i64 || n < -(MAX_EXACT_INT as i64) {
+		Err(fmt!("%s %s can't be represented exactly as a Lua number", name, n.to_str()))
+	} else {
+		Ok(n as float)
+	}
+}
+
+fn exact_unsigned(n: u64, name: &str) -> Result<float, ~str> {
+	if n > MAX_EXACT_INT {
+		Err(fmt!("%s %s can't be represented exactly as a Lua number", name, n.to_str()))
+	} else {
+		Ok(n as float)
 	}
 }
 
+/// Values beyond 2^53 are rounded by `lua_push` and refused by `try_lua_push`.
 impl LuaPush for int {
 	fn lua_push(&self, state: &state::State) {
 		state.push_int(*self);
 	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		match exact_signed(*self as i64, "int") {
+			Ok(_) => { state.push_int(*self); Ok(()) },
+			Err(msg) => Err(msg)
+		}
+	}
 }
 
 impl<'self> LuaPush for &'self int {
 	fn lua_push(&self, state: &state::State) {
-		state.push_int(**self);
+		(**self).lua_push(state);
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		(**self).try_lua_push(state)
 	}
 }
 
 impl LuaTo for int {
-	fn lua_to(state: &state::State, index: int) -> int {
-		return state.to_int(index);
+	fn try_lua_to(state: &state::State, index: int) -> Result<int, LuaErr> {
+		state.try_to_int(index)
+	}
+}
+
+/**
+ * A number converted by clamping it into range instead of returning an error.
+ *
+ * Fractions are truncated toward zero, out of range values saturate at the
+ * type's min or max and NaN becomes 0. Integers past 2^53 are pushed rounded
+ * to the nearest Lua number.
+ */
+#[deriving(Eq, Clone)]
+pub struct Saturating<T>(T);
+
+// try_lua_push isn't overridden, so pushing is always the lossy lua_push
+impl<T: LuaPush> LuaPush for Saturating<T> {
+	fn lua_push(&self, state: &state::State) {
+		(**self).lua_push(state);
+	}
+}
+
+// Integers are pushed as Lua numbers, which are exact up to 2^53.
+macro_rules! int_impls(
+	// Reading is range checked for every type
+	(to $T:ty, $name:expr) => (
+		impl LuaTo for $T {
+			fn try_lua_to(state: &state::State, index: int) -> Result<$T, LuaErr> {
+				let min: $T = Bounded::min_value();
+				let max: $T = Bounded::max_value();
+				match state.try_to_integer(index, min as float, max as float + 1.0, $name) {
+					Ok(n) => Ok(n as $T),
+					Err(err) => Err(err)
+				}
+			}
+		}
+	);
+	($T:ty, $name:expr) => (
+		impl LuaPush for $T {
+			fn lua_push(&self, state: &state::State) {
+				state.push_float(*self as float);
+			}
+		}
+
+		int_impls!(to $T, $name)
+	);
+	// Types wider than 53 bits round in lua_push and refuse in try_lua_push
+	($T:ty, $name:expr, $exact:ident, $wide:ty) => (
+		impl LuaPush for $T {
+			fn lua_push(&self, state: &state::State) {
+				state.push_float(*self as float);
+			}
+
+			fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+				match $exact(*self as $wide, $name) {
+					Ok(n) => { state.push_float(n); Ok(()) },
+					Err(msg) => Err(msg)
+				}
+			}
+		}
+
+		int_impls!(to $T, $name)
+	);
+)
+
+int_impls!(i8, "i8")
+int_impls!(i16, "i16")
+int_impls!(i32, "i32")
+int_impls!(i64, "i64", exact_signed, i64)
+int_impls!(u8, "u8")
+int_impls!(u16, "u16")
+int_impls!(u32, "u32")
+int_impls!(u64, "u64", exact_unsigned, u64)
+int_impls!(uint, "uint", exact_unsigned, u64)
+
+macro_rules! saturating_impls(
+	($($T:ty),+) => ($(
+		impl LuaTo for Saturating<$T> {
+			fn try_lua_to(state: &state::State, index: int) -> Result<Saturating<$T>, LuaErr> {
+				let min: $T = Bounded::min_value();
+				let max: $T = Bounded::max_value();
+				match state.try_to_float(index) {
+					Ok(n) if n.is_NaN() => Ok(Saturating(0 as $T)),
+					Ok(n) if n <= min as float => Ok(Saturating(min)),
+					Ok(n) if n >= max as float => Ok(Saturating(max)),
+					Ok(n) => Ok(Saturating(n.trunc() as $T)),
+					Err(err) => Err(err)
+				}
+			}
+		}
+	)+);
+)
+
+saturating_impls!(int, i8, i16, i32, i64, uint, u8, u16, u32, u64)
+
+impl LuaPush for f32 {
+	fn lua_push(&self, state: &state::State) {
+		state.push_float(*self as float);
+	}
+}
+
+/// Finite numbers beyond the range of f32 are an error, precision loss isn't.
+impl LuaTo for f32 {
+	fn try_lua_to(state: &state::State, index: int) -> Result<f32, LuaErr> {
+		match state.try_to_float(index) {
+			Ok(n) if n.is_finite() && (n > f32::max_value as float || n < -f32::max_value as float) => {
+				Err(state::runtime_err(~"number out of range for f32"))
+			},
+			Ok(n) => Ok(n as f32),
+			Err(err) => Err(err)
+		}
+	}
+}
+
+impl LuaTo for Saturating<f32> {
+	fn try_lua_to(state: &state::State, index: int) -> Result<Saturating<f32>, LuaErr> {
+		let max = f32::max_value as float;
+		match state.try_to_float(index) {
+			Ok(n) if n.is_finite() && n > max => Ok(Saturating(f32::max_value)),
+			Ok(n) if n.is_finite() && n < -max => Ok(Saturating(-f32::max_value)),
+			Ok(n) => Ok(Saturating(n as f32)),
+			Err(err) => Err(err)
+		}
 	}
 }
 
@@ -215,89 +792,1394 @@ impl<'self> LuaPush for &'self str {
 }
 
 impl LuaTo for ~str {
-	fn lua_to(state: &state::State, index: int) -> ~str {
-		state.to_str(index)
+	fn try_lua_to(state: &state::State, index: int) -> Result<~str, LuaErr> {
+		state.try_to_str(index)
 	}
 }
 
-impl<T: LuaPush> LuaPush for ~[T] {
+/// A byte string, pushed and read as a Lua string without any UTF-8 checks.
+#[deriving(Eq, Clone)]
+pub struct Bytes(~[u8]);
+
+impl LuaPush for Bytes {
 	fn lua_push(&self, state: &state::State) {
-		state.new_table();
+		state.push_bytes((**self).as_slice());
+	}
+}
 
-		let mut i: int = 1;
-		for v in self.iter() {
-			v.lua_push(state);
-			state.raw_set_i(-2, i);
-			i += 1;
+impl LuaTo for Bytes {
+	fn try_lua_to(state: &state::State, index: int) -> Result<Bytes, LuaErr> {
+		match state.try_to_bytes(index) {
+			Ok(b) => Ok(Bytes(b)),
+			Err(err) => Err(err)
+		}
+	}
+}
+
+/**
+ * A string borrowed from Lua without copying it.
+ *
+ * Holds a reference to the Lua string so it can't be collected while the
+ * handle is alive, which makes it cheap to take large strings as callback
+ * arguments. Checked to be valid UTF-8 when converted.
+ */
+pub struct LuaStr {
+	priv anchor: LuaRef,
+	priv ptr: *u8,
+	priv len: uint
+}
+
+impl LuaStr {
+	/// Borrow the contents for as long as the handle lives.
+	pub fn as_str<'a>(&'a self) -> &'a str {
+		// Lua strings end in a zero, which the str slice layout counts in its length
+		unsafe { cast::transmute((self.ptr, self.len + 1)) }
+	}
+
+	pub fn as_bytes<'a>(&'a self) -> &'a [u8] {
+		unsafe { cast::transmute((self.ptr, self.len)) }
+	}
+
+	/// Length in bytes.
+	pub fn len(&self) -> uint {
+		self.len
+	}
+}
+
+impl LuaPush for LuaStr {
+	fn lua_push(&self, state: &state::State) {
+		self.anchor.lua_push(state);
+	}
+}
+
+impl LuaTo for LuaStr {
+	fn try_lua_to(state: &state::State, index: int) -> Result<LuaStr, LuaErr> {
+		match state.index_type(index) {
+			state::TString => {},
+			t => { return Err(state.type_err("string", t)); }
+		}
+
+		let (ptr, len) = unsafe { state.to_bytes_raw(index) };
+		let bytes: &[u8] = unsafe { cast::transmute((ptr, len)) };
+		if !str::is_utf8(bytes) {
+			return Err(state::runtime_err(~"string is not valid UTF-8"));
 		}
+		Ok(LuaStr{ anchor: LuaRef::new(state, index), ptr: ptr, len: len })
+	}
+}
+
+impl<T: LuaPush> LuaPush for ~[T] {
+	fn lua_push(&self, state: &state::State) {
+		push_sequence(state, self.iter(), false);
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		push_sequence(state, self.iter(), true)
 	}
 }
 
 impl<T: LuaTo> LuaTo for ~[T] {
-	fn lua_to(state: &state::State, index: int) -> ~[T] {
+	fn try_lua_to(state: &state::State, index: int) -> Result<~[T], LuaErr> {
+		match state.index_type(index) {
+			state::TTable => {},
+			t => { return Err(state.type_err("table", t)); }
+		}
+
+		let index = state.abs_index(index);
+		let top = state.get_top();
 		let mut vect = ~[];
 
 		state.push_nil();
-		while state.next(index - 1) {
-			vect.push( LuaTo::lua_to(state, -1) );
+		while state.next(index) {
+			match LuaTo::try_lua_to(state, -1) {
+				Ok(v) => { vect.push(v); },
+				Err(err) => {
+					state.set_top(top);
+					return Err(err);
+				}
+			}
 			state.pop(1);
 		}
 
-		return vect;
+		return Ok(vect);
+	}
+}
+
+// Push key, value pairs as a table.
+fn push_map<'a, K: LuaPush, V: LuaPush, I: Iterator<(&'a K, &'a V)>>(state: &state::State, mut pairs: I,
+		strict: bool) -> Result<(), ~str> {
+	state.new_table();
+	for (k, v) in pairs {
+		match (push_checked(state, k, strict), push_checked(state, v, strict)) {
+			(Ok(()), Ok(())) => {},
+			(Err(msg), _) | (_, Err(msg)) => { return Err(msg); }
+		}
+		state.raw_set(-3);
 	}
+	Ok(())
 }
 
 impl<'self, K: LuaPush + Hash + Eq, V: LuaPush> LuaPush for &'self HashMap<K, V> {
 	fn lua_push(&self, state: &state::State) {
-		state.new_table();
+		push_map(state, self.iter(), false);
+	}
 
-		for kv in self.iter() {
-			match kv {
-				(k, v) => {
-					k.lua_push(state);
-					v.lua_push(state);
-					state.raw_set(-3);
-				}
-			};
-		}
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		push_map(state, self.iter(), true)
 	}
 }
 
 impl<K: LuaPush + Hash + Eq, V: LuaPush> LuaPush for HashMap<K, V> {
 	fn lua_push(&self, state: &state::State) {
-		state.new_table();
-
-		for kv in self.iter() {
-			match kv {
-				(k, v) => {
-					k.lua_push(state);
-					v.lua_push(state);
+		push_map(state, self.iter(), false);
+	}
 
-					state.raw_set(-3);
-				}
-			};
-		}
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		push_map(state, self.iter(), true)
 	}
 }
 
 impl<K: LuaTo + Hash + Eq, V: LuaTo> LuaTo for HashMap<K, V> {
-	fn lua_to(state: &state::State, index: int) -> HashMap<K, V> {
+	fn try_lua_to(state: &state::State, index: int) -> Result<HashMap<K, V>, LuaErr> {
+		match state.index_type(index) {
+			state::TTable => {},
+			t => { return Err(state.type_err("table", t)); }
+		}
+
+		let index = state.abs_index(index);
+		let top = state.get_top();
 		let mut m: HashMap<K, V> = HashMap::new();
 
 		state.push_nil();
-		while state.next(index - 1) {
-			let k: K = LuaTo::lua_to(state, -2);
-			let v: V = LuaTo::lua_to(state, -1);
+		while state.next(index) {
+			let kv: (Result<K, LuaErr>, Result<V, LuaErr>) =
+				(LuaTo::try_lua_to(state, -2), LuaTo::try_lua_to(state, -1));
+			match kv {
+				(Ok(k), Ok(v)) => { m.swap(k, v); },
+				(Err(err), _) | (_, Err(err)) => {
+					state.set_top(top);
+					return Err(err);
+				}
+			}
 			state.pop(1);
-			m.swap(k, v);
 		}
-		return m;
+		return Ok(m);
 	}
 }
 
-impl LuaPush for LuaCallback {
+impl<K: LuaPush + TotalOrd, V: LuaPush> LuaPush for TreeMap<K, V> {
 	fn lua_push(&self, state: &state::State) {
-		state.push_function(*self);
+		push_map(state, self.iter(), false);
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		push_map(state, self.iter(), true)
+	}
+}
+
+impl<K: LuaTo + TotalOrd, V: LuaTo> LuaTo for TreeMap<K, V> {
+	fn try_lua_to(state: &state::State, index: int) -> Result<TreeMap<K, V>, LuaErr> {
+		match state.index_type(index) {
+			state::TTable => {},
+			t => { return Err(state.type_err("table", t)); }
+		}
+
+		let index = state.abs_index(index);
+		let top = state.get_top();
+		let mut m: TreeMap<K, V> = TreeMap::new();
+
+		state.push_nil();
+		while state.next(index) {
+			let kv: (Result<K, LuaErr>, Result<V, LuaErr>) =
+				(LuaTo::try_lua_to(state, -2), LuaTo::try_lua_to(state, -1));
+			match kv {
+				(Ok(k), Ok(v)) => { m.insert(k, v); },
+				(Err(err), _) | (_, Err(err)) => {
+					state.set_top(top);
+					return Err(err);
+				}
+			}
+			state.pop(1);
+		}
+		return Ok(m);
+	}
+}
+
+// Sets are tables with their members as keys and true as every value.
+fn push_set<'a, T: LuaPush, I: Iterator<&'a T>>(state: &state::State, mut members: I,
+		strict: bool) -> Result<(), ~str> {
+	state.new_table();
+	for k in members {
+		match push_checked(state, k, strict) {
+			Ok(()) => {},
+			Err(msg) => { return Err(msg); }
+		}
+		state.push_bool(true);
+		state.raw_set(-3);
+	}
+	Ok(())
+}
+
+// Read the keys of a set table, skipping keys whose value is false.
+fn set_members<T: LuaTo>(state: &state::State, index: int) -> Result<~[T], LuaErr> {
+	match state.index_type(index) {
+		state::TTable => {},
+		t => { return Err(state.type_err("table", t)); }
+	}
+
+	let index = state.abs_index(index);
+	let top = state.get_top();
+	let mut members = ~[];
+
+	state.push_nil();
+	while state.next(index) {
+		if state.to_truthy(-1) {
+			match LuaTo::try_lua_to(state, -2) {
+				Ok(k) => { members.push(k); },
+				Err(err) => {
+					state.set_top(top);
+					return Err(err);
+				}
+			}
+		}
+		state.pop(1);
+	}
+	return Ok(members);
+}
+
+impl<T: LuaPush + Hash + Eq> LuaPush for HashSet<T> {
+	fn lua_push(&self, state: &state::State) {
+		push_set(state, self.iter(), false);
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		push_set(state, self.iter(), true)
+	}
+}
+
+impl<T: LuaTo + Hash + Eq> LuaTo for HashSet<T> {
+	fn try_lua_to(state: &state::State, index: int) -> Result<HashSet<T>, LuaErr> {
+		match set_members(state, index) {
+			Ok(members) => Ok(members.move_iter().collect()),
+			Err(err) => Err(err)
+		}
+	}
+}
+
+impl<T: LuaPush + TotalOrd> LuaPush for TreeSet<T> {
+	fn lua_push(&self, state: &state::State) {
+		push_set(state, self.iter(), false);
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		push_set(state, self.iter(), true)
+	}
+}
+
+impl<T: LuaTo + TotalOrd> LuaTo for TreeSet<T> {
+	fn try_lua_to(state: &state::State, index: int) -> Result<TreeSet<T>, LuaErr> {
+		match set_members(state, index) {
+			Ok(members) => Ok(members.move_iter().collect()),
+			Err(err) => Err(err)
+		}
+	}
+}
+
+// Push values as a sequence table, t[1] to t[n].
+fn push_sequence<'a, T: LuaPush, I: Iterator<&'a T>>(state: &state::State, mut values: I,
+		strict: bool) -> Result<(), ~str> {
+	state.new_table();
+	let mut i: int = 1;
+	for v in values {
+		match push_checked(state, v, strict) {
+			Ok(()) => {},
+			Err(msg) => { return Err(msg); }
+		}
+		state.raw_set_i(-2, i);
+		i += 1;
+	}
+	Ok(())
+}
+
+// Read t[1] to t[#t] in order. Fails if len is given and #t differs.
+fn sequence_values<T: LuaTo>(state: &state::State, index: int, len: Option<uint>) -> Result<~[T], LuaErr> {
+	match state.index_type(index) {
+		state::TTable => {},
+		t => { return Err(state.type_err("table", t)); }
+	}
+
+	let index = state.abs_index(index);
+	let n = state.obj_len(index);
+	match len {
+		Some(len) if len != n => {
+			return Err(state::runtime_err(fmt!("table of %u values expected, got %u", len, n)));
+		},
+		_ => {}
+	}
+
+	let mut values = vec::with_capacity(n);
+	let mut i = 1;
+	while i <= n {
+		state.raw_get_i(index, i as int);
+		let r = LuaTo::try_lua_to(state, -1);
+		state.pop(1);
+		match r {
+			Ok(v) => { values.push(v); },
+			Err(err) => { return Err(err); }
+		}
+		i += 1;
+	}
+	return Ok(values);
+}
+
+/// Deques are sequence tables, front first.
+impl<T: LuaPush> LuaPush for RingBuf<T> {
+	fn lua_push(&self, state: &state::State) {
+		push_sequence(state, self.iter(), false);
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		push_sequence(state, self.iter(), true)
+	}
+}
+
+impl<T: LuaTo> LuaTo for RingBuf<T> {
+	fn try_lua_to(state: &state::State, index: int) -> Result<RingBuf<T>, LuaErr> {
+		match sequence_values(state, index, None) {
+			Ok(values) => {
+				let mut d = RingBuf::new();
+				for v in values.move_iter() {
+					d.push_back(v);
+				}
+				Ok(d)
+			},
+			Err(err) => Err(err)
+		}
+	}
+}
+
+// Fixed size arrays are sequence tables that must have exactly N values.
+macro_rules! array_impls(
+	($($N:expr),+) => ($(
+		impl<T: LuaPush> LuaPush for [T, ..$N] {
+			fn lua_push(&self, state: &state::State) {
+				push_sequence(state, self.iter(), false);
+			}
+
+			fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+				push_sequence(state, self.iter(), true)
+			}
+		}
+
+		impl<T: LuaTo> LuaTo for [T, ..$N] {
+			fn try_lua_to(state: &state::State, index: int) -> Result<[T, ..$N], LuaErr> {
+				match sequence_values(state, index, Some($N)) {
+					Ok(values) => unsafe {
+						// values has exactly N elements, so every slot gets initialized
+						let mut arr: [T, ..$N] = intrinsics::uninit();
+						let mut i = 0;
+						for v in values.move_iter() {
+							intrinsics::move_val_init(&mut arr[i], v);
+							i += 1;
+						}
+						Ok(arr)
+					},
+					Err(err) => Err(err)
+				}
+			}
+		}
+	)+);
+)
+
+array_impls!(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16)
+
+impl LuaPush for LuaCallback {
+	fn lua_push(&self, state: &state::State) {
+		state.push_function(*self);
+	}
+}
+
+/// Callbacks can return a `Result`, an `Err` is raised as a Lua error.
+impl<T: LuaPush, E: ToStr> LuaPush for Result<T, E> {
+	fn lua_push(&self, state: &state::State) {
+		match *self {
+			Ok(ref v) => v.lua_push(state),
+			Err(ref e) => fail!(e.to_str())
+		}
+	}
+
+	fn try_lua_push(&self, state: &state::State) -> Result<(), ~str> {
+		match *self {
+			Ok(ref v) => v.try_lua_push(state),
+			Err(ref e) => Err(e.to_str())
+		}
+	}
+}
+
+/**
+ * Run the body of a Rust callback called from Lua, used by `lua_cb!` and friends.
+ *
+ * The body runs on the calling task, so task-local data stays visible. A
+ * failure in the body is caught before it can unwind into Lua. It and an
+ * error message returned by the body are raised as a Lua error once the
+ * body's frames are gone. A Lua error raised while the body runs would skip
+ * its destructors, so the body should use protected calls: `LuaTable`,
+ * `LuaFunction` and `Lua.global()` are, `State.error()` isn't.
+ */
+pub fn run_callback(raw_state: *state::lua_State, name: &'static str,
+		f: &fn(&state::State) -> Result<int, ~str>) -> c_int {
+	let mut result = None;
+	unsafe {
+		let task: *mut Task = Local::unsafe_borrow();
+		do (*task).unwinder.try {
+			result = Some(do state::with_state(raw_state) |state| { f(state) });
+		}
+		if result.is_none() {
+			// The failure was caught here, the task carries on
+			(*task).unwinder.unwinding = false;
+		}
+	}
+
+	do state::with_state(raw_state) |state| {
+		match result {
+			Some(Ok(n)) => n as c_int,
+			Some(Err(msg)) => state.raise_error(msg),
+			None => state.raise_error(fmt!("error in '%s' (Rust callback failed)", name))
+		}
+	}
+}
+
+/// Build the message for a callback argument that couldn't be converted.
+pub fn arg_error(i: int, name: &str, err: LuaErr) -> ~str {
+	let msg = match err {
+		state::Runtime(err) => err.msg,
+		err => err.to_str()
+	};
+	fmt!("bad argument #%d to '%s' (%s)", i, name, msg)
+}
+
+impl LuaPush for LuaRef {
+	fn lua_push(&self, state: &state::State) {
+		state.push_ref(self);
+	}
+}
+
+impl LuaTo for LuaRef {
+	fn try_lua_to(state: &state::State, index: int) -> Result<LuaRef, LuaErr> {
+		match state.index_type(index) {
+			state::TNone => Err(state.type_err("value", state::TNone)),
+			_ => Ok(LuaRef::new(state, index))
+		}
+	}
+}
+
+impl LuaRef {
+	/// Convert the referenced value.
+	pub fn get<T: LuaTo>(&self) -> Result<T, LuaErr> {
+		do state::with_state(self.state()) |state| {
+			state.push_ref(self);
+			let res = LuaTo::try_lua_to(state, -1);
+			state.pop(1);
+			res
+		}
+	}
+}
+
+/**
+ * A Lua function that can be called from Rust.
+ *
+ * Arguments are pushed with `LuaPush`, so several arguments are passed as a
+ * tuple. Results are read with `LuaTo`, and a tuple return type requests one
+ * result per element.
+ */
+#[deriving(Clone)]
+pub struct LuaFunction {
+	priv func: LuaRef
+}
+
+impl LuaFunction {
+	/**
+	 * Call the function.
+	 *
+	 * Fails if the call raises an error or the results are the wrong type.
+	 */
+	pub fn call<A: LuaPush, R: LuaTo>(&self, args: A) -> R {
+		match self.try_call(args) {
+			Ok(r) => r,
+			Err(err) => fail!(fmt!("LuaFunction.call() failed: %s", err.to_str()))
+		}
+	}
+
+	/// Call the function, returning an error if it raises one or the results are the wrong type.
+	pub fn try_call<A: LuaPush, R: LuaTo>(&self, args: A) -> Result<R, LuaErr> {
+		do state::with_state(self.func.state()) |state| {
+			let top = state.get_top();
+			self.func.lua_push(state);
+			args.lua_push(state);
+			let nargs = state.get_top() - top - 1;
+
+			match state.try_pcall(nargs, LuaTo::lua_size(None::<R>), 0) {
+				Ok(()) => {
+					let r: Result<R, LuaErr> = LuaTo::try_lua_to(state, top + 1);
+					state.set_top(top);
+					r
+				},
+				Err(err) => {
+					state.set_top(top);
+					Err(err)
+				}
+			}
+		}
+	}
+}
+
+impl LuaPush for LuaFunction {
+	fn lua_push(&self, state: &state::State) {
+		self.func.lua_push(state);
+	}
+}
+
+impl LuaTo for LuaFunction {
+	fn try_lua_to(state: &state::State, index: int) -> Result<LuaFunction, LuaErr> {
+		match state.index_type(index) {
+			state::TFunction => Ok(LuaFunction{ func: LuaRef::new(state, index) }),
+			t => Err(state.type_err("function", t))
+		}
+	}
+}
+
+/**
+ * A Lua table held by reference.
+ *
+ * `get` and `set` go through metamethods like indexing in Lua does, the `raw_`
+ * variants don't.
+ */
+#[deriving(Clone)]
+pub struct LuaTable {
+	priv table: LuaRef
+}
+
+impl LuaTable {
+	/**
+	 * Get the value stored under key.
+	 *
+	 * Fails if the value is the wrong type.
+	 */
+	pub fn get<K: LuaPush, V: LuaTo>(&self, key: K) -> V {
+		match self.try_get(key) {
+			Ok(v) => v,
+			Err(err) => fail!(fmt!("LuaTable.get() failed: %s", err.to_str()))
+		}
+	}
+
+	/**
+	 * Get the value stored under key, or an error if it's the wrong type or
+	 * an `__index` metamethod raises one.
+	 */
+	pub fn try_get<K: LuaPush, V: LuaTo>(&self, key: K) -> Result<V, LuaErr> {
+		do self.with_table |state, t| {
+			key.lua_push(state);
+			match state.try_get_table(t) {
+				Ok(()) => LuaTo::try_lua_to(state, -1),
+				Err(err) => Err(err)
+			}
+		}
+	}
+
+	/// Like `get`, but without invoking metamethods.
+	pub fn raw_get<K: LuaPush, V: LuaTo>(&self, key: K) -> V {
+		match self.try_raw_get(key) {
+			Ok(v) => v,
+			Err(err) => fail!(fmt!("LuaTable.raw_get() failed: %s", err.to_str()))
+		}
+	}
+
+	/// Like `try_get`, but without invoking metamethods.
+	pub fn try_raw_get<K: LuaPush, V: LuaTo>(&self, key: K) -> Result<V, LuaErr> {
+		do self.with_table |state, t| {
+			key.lua_push(state);
+			state.raw_get(t);
+			LuaTo::try_lua_to(state, -1)
+		}
+	}
+
+	/**
+	 * Store a value under key.
+	 *
+	 * Fails if a `__newindex` metamethod raises an error.
+	 */
+	pub fn set<K: LuaPush, V: LuaPush>(&self, key: K, val: V) {
+		match self.try_set(key, val) {
+			Err(err) => fail!(fmt!("LuaTable.set() failed: %s", err.to_str())),
+			_ => {}
+		}
+	}
+
+	/// Store a value under key, or return the error a `__newindex` metamethod raises
+	/// or an error if key or value can't be pushed exactly.
+	pub fn try_set<K: LuaPush, V: LuaPush>(&self, key: K, val: V) -> Result<(), LuaErr> {
+		do self.with_table |state, t| {
+			match (key.try_lua_push(state), val.try_lua_push(state)) {
+				(Ok(()), Ok(())) => state.try_set_table(t),
+				(Err(msg), _) | (_, Err(msg)) => Err(state::runtime_err(msg))
+			}
+		}
+	}
+
+	/// Like `set`, but without invoking metamethods.
+	pub fn raw_set<K: LuaPush, V: LuaPush>(&self, key: K, val: V) {
+		do self.with_table |state, t| {
+			key.lua_push(state);
+			val.lua_push(state);
+			state.raw_set(t);
+		}
+	}
+
+	/// The length of the table as given by the `#` operator, without metamethods.
+	pub fn len(&self) -> uint {
+		do self.with_table |state, t| {
+			state.obj_len(t)
+		}
+	}
+
+	/// Whether there is a non-nil value stored under key, without metamethods.
+	pub fn contains_key<K: LuaPush>(&self, key: K) -> bool {
+		do self.with_table |state, t| {
+			key.lua_push(state);
+			state.raw_get(t);
+			match state.index_type(-1) {
+				state::TNil => false,
+				_ => true
+			}
+		}
+	}
+
+	/**
+	 * Get a (Key, Value) iterator over every entry in the table.
+	 *
+	 * Fails if an entry is the wrong type.
+	 */
+	pub fn pairs<'a, K: LuaTo, V: LuaTo>(&'a self) -> LuaTablePairs<'a, K, V> {
+		let top = do state::with_state(self.table.state()) |state| {
+			let top = state.get_top();
+			self.table.lua_push(state);
+			top
+		};
+		LuaTablePairs{ table: self, top: top, started: false, closed: false }
+	}
+
+	/**
+	 * Get an iterator over the values at keys 1, 2, ... up to the first nil.
+	 *
+	 * Fails if a value is the wrong type.
+	 */
+	pub fn sequence<'a, V: LuaTo>(&'a self) -> LuaTableSequence<'a, V> {
+		LuaTableSequence{ table: self, i: 1, closed: false }
+	}
+
+	/// Set the metatable of the table.
+	pub fn set_metatable(&self, mt: &LuaTable) {
+		do self.with_table |state, t| {
+			mt.lua_push(state);
+			state.set_metatable(t);
+		}
+	}
+
+	/// Push the table, run f with its stack index and restore the stack.
+	fn with_table<T>(&self, f: &fn(&state::State, int) -> T) -> T {
+		do state::with_state(self.table.state()) |state| {
+			let top = state.get_top();
+			self.table.lua_push(state);
+			let r = f(state, top + 1);
+			state.set_top(top);
+			r
+		}
+	}
+}
+
+impl LuaPush for LuaTable {
+	fn lua_push(&self, state: &state::State) {
+		self.table.lua_push(state);
+	}
+}
+
+impl LuaTo for LuaTable {
+	fn try_lua_to(state: &state::State, index: int) -> Result<LuaTable, LuaErr> {
+		match state.index_type(index) {
+			state::TTable => Ok(LuaTable{ table: LuaRef::new(state, index) }),
+			t => Err(state.type_err("table", t))
+		}
+	}
+}
+
+/// Iterator returned by `LuaTable.pairs()`. Keeps the table on the stack while alive.
+pub struct LuaTablePairs<'self, K, V> {
+	priv table: &'self LuaTable,
+	priv top: int,
+	priv started: bool,
+	priv closed: bool
+}
+
+impl<'self, K: LuaTo, V: LuaTo> Iterator<(K, V)> for LuaTablePairs<'self, K, V> {
+	fn next(&mut self) -> Option<(K, V)> {
+		if self.closed {
+			return None;
+		}
+		let t = self.top + 1;
+		do state::with_state(self.table.table.state()) |state| {
+			if !self.started {
+				state.push_nil();
+				self.started = true;
+			}
+			if !state.next(t) {
+				self.closed = true;
+				None
+			} else {
+				let ret: (K, V) = (LuaTo::lua_to(state, -2), LuaTo::lua_to(state, -1));
+				state.pop(1);
+				Some(ret)
+			}
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl<'self, K, V> Drop for LuaTablePairs<'self, K, V> {
+	fn drop(&mut self) {
+		let top = self.top;
+		do state::with_state(self.table.table.state()) |state| {
+			state.set_top(top);
+		}
+	}
+}
+
+/// Iterator returned by `LuaTable.sequence()`.
+pub struct LuaTableSequence<'self, V> {
+	priv table: &'self LuaTable,
+	priv i: int,
+	priv closed: bool
+}
+
+impl<'self, V: LuaTo> Iterator<V> for LuaTableSequence<'self, V> {
+	fn next(&mut self) -> Option<V> {
+		if self.closed {
+			return None;
+		}
+		let i = self.i;
+		let ret: Option<V> = do self.table.with_table |state, t| {
+			state.raw_get_i(t, i);
+			match state.index_type(-1) {
+				state::TNil => None,
+				_ => Some(LuaTo::lua_to(state, -1))
+			}
+		};
+		match ret {
+			Some(_) => { self.i += 1; },
+			None => { self.closed = true; }
+		}
+		ret
+	}
+}
+
+/// Outcome of resuming a `LuaThread`.
+pub enum ResumeResult {
+	/// The coroutine yielded these values and can be resumed again.
+	Yielded(~[LuaValue]),
+	/// The coroutine returned these values and is dead.
+	Finished(~[LuaValue]),
+	/**
+	 * The coroutine raised an error and is dead, or the values it passed
+	 * back couldn't be converted. In the latter case it may still be resumed.
+	 */
+	Error(LuaErr)
+}
+
+pub enum ThreadStatus {
+	Suspended,
+	Dead,
+	Errored
+}
+
+/**
+ * A Lua coroutine.
+ *
+ * Values passed to `resume` are the function's arguments on the first call
+ * and the results of `coroutine.yield` afterwards.
+ */
+#[deriving(Clone)]
+pub struct LuaThread {
+	priv thread: LuaRef,
+	priv co: *state::lua_State
+}
+
+impl LuaThread {
+	pub fn resume<A: LuaPush>(&self, args: A) -> ResumeResult {
+		match self.status() {
+			Suspended => {},
+			_ => { return Error(state::runtime_err(~"cannot resume dead coroutine")); }
+		}
+
+		do state::with_state(self.thread.state()) |main| {
+			do state::with_state(self.co) |co| {
+				let top = co.get_top();
+				args.lua_push(co);
+				let nargs = co.get_top() - top;
+
+				match co.try_resume(nargs) {
+					Ok(false) => match self.take_values(main, co) {
+						Ok(values) => Finished(values),
+						Err(err) => Error(err)
+					},
+					Ok(true) => match self.take_values(main, co) {
+						Ok(values) => Yielded(values),
+						Err(err) => Error(err)
+					},
+					Err(err) => Error(err)
+				}
+			}
+		}
+	}
+
+	pub fn status(&self) -> ThreadStatus {
+		do state::with_state(self.co) |co| {
+			match co.status() {
+				state::LUA_YIELD => Suspended,
+				// A coroutine that hasn't started yet has its function on the stack
+				0 if co.get_top() > 0 => Suspended,
+				0 => Dead,
+				_ => Errored
+			}
+		}
+	}
+
+	/// Move every value on the coroutine's stack to the main stack and convert them.
+	fn take_values(&self, main: &state::State, co: &state::State) -> Result<~[LuaValue], LuaErr> {
+		let n = co.get_top();
+		let top = main.get_top();
+		co.xmove(main, n);
+
+		let mut values = ~[];
+		for i in range(top + 1, top + n + 1) {
+			match LuaTo::try_lua_to(main, i) {
+				Ok(v) => values.push(v),
+				Err(err) => {
+					main.set_top(top);
+					return Err(err);
+				}
+			}
+		}
+		main.set_top(top);
+		Ok(values)
+	}
+}
+
+impl LuaPush for LuaThread {
+	fn lua_push(&self, state: &state::State) {
+		self.thread.lua_push(state);
+	}
+}
+
+impl LuaTo for LuaThread {
+	fn try_lua_to(state: &state::State, index: int) -> Result<LuaThread, LuaErr> {
+		match state.index_type(index) {
+			state::TThread => Ok(LuaThread{
+				thread: LuaRef::new(state, index),
+				co: state.to_thread(index).raw()
+			}),
+			t => Err(state.type_err("thread", t))
+		}
+	}
+}
+
+/**
+ * An owned copy of any Lua value.
+ *
+ * Tables are read recursively: a table whose keys are exactly 1..n becomes an
+ * `Array`, any other table becomes a `Table` of (key, value) pairs. Strings
+ * that aren't valid UTF-8 become `Binary`. Functions,
+ * full userdata and threads can't be copied out of Lua, so they are held by a
+ * registry reference instead.
+ */
+#[deriving(Clone)]
+pub enum LuaValue {
+	Nil,
+	Boolean(bool),
+	Number(float),
+	String(~str),
+	Binary(~[u8]),
+	Table(~[(LuaValue, LuaValue)]),
+	Array(~[LuaValue]),
+	LightUserData(*c_void),
+	Function(LuaRef),
+	UserData(LuaRef),
+	Thread(LuaRef)
+}
+
+impl LuaValue {
+	/// The Lua type of the value.
+	pub fn lua_type(&self) -> LuaType {
+		match *self {
+			Nil              => state::TNil,
+			Boolean(_)       => state::TBoolean,
+			Number(_)        => state::TNumber,
+			String(_)        => state::TString,
+			Binary(_)        => state::TString,
+			Table(_)         => state::TTable,
+			Array(_)         => state::TTable,
+			LightUserData(_) => state::TLightUserData,
+			Function(_)      => state::TFunction,
+			UserData(_)      => state::TUserData,
+			Thread(_)        => state::TThread
+		}
+	}
+}
+
+impl LuaPush for LuaValue {
+	fn lua_push(&self, state: &state::State) {
+		match *self {
+			Nil              => state.push_nil(),
+			Boolean(b)       => state.push_bool(b),
+			Number(n)        => state.push_float(n),
+			String(ref s)    => state.push_str(*s),
+			Binary(ref b)    => state.push_bytes(*b),
+			LightUserData(p) => state.push_light_userdata(p),
+			Table(ref pairs) => {
+				state.create_table(0, pairs.len() as int);
+				for kv in pairs.iter() {
+					match *kv {
+						(ref k, ref v) => {
+							k.lua_push(state);
+							v.lua_push(state);
+							state.raw_set(-3);
+						}
+					}
+				}
+			},
+			Array(ref items) => {
+				state.create_table(items.len() as int, 0);
+				let mut i: int = 1;
+				for v in items.iter() {
+					v.lua_push(state);
+					state.raw_set_i(-2, i);
+					i += 1;
+				}
+			},
+			Function(ref r) | UserData(ref r) | Thread(ref r) => r.lua_push(state)
+		}
+	}
+}
+
+impl LuaTo for LuaValue {
+	fn try_lua_to(state: &state::State, index: int) -> Result<LuaValue, LuaErr> {
+		let mut seen = ~[];
+		value_at(state, index, &mut seen)
+	}
+}
+
+fn value_at(state: &state::State, index: int, seen: &mut ~[*c_void]) -> Result<LuaValue, LuaErr> {
+	match state.index_type(index) {
+		state::TNone | state::TNil => Ok(Nil),
+		state::TBoolean            => Ok(Boolean(state.to_bool(index))),
+		state::TNumber             => Ok(Number(state.to_float(index))),
+		state::TString             => {
+			let bytes = state.to_bytes(index);
+			if str::is_utf8(bytes) {
+				Ok(String(str::from_utf8_owned(bytes)))
+			} else {
+				Ok(Binary(bytes))
+			}
+		},
+		state::TTable              => table_value_at(state, index, seen),
+		state::TLightUserData      => Ok(LightUserData(state.to_userdata(index))),
+		state::TFunction           => Ok(Function(LuaRef::new(state, index))),
+		state::TUserData           => Ok(UserData(LuaRef::new(state, index))),
+		state::TThread             => Ok(Thread(LuaRef::new(state, index))),
+		t                          => Err(state.type_err("value", t))
+	}
+}
+
+fn table_value_at(state: &state::State, index: int, seen: &mut ~[*c_void]) -> Result<LuaValue, LuaErr> {
+	let ptr = state.to_pointer(index);
+	if seen.contains(&ptr) {
+		return Err(state::runtime_err(~"can't copy a table that contains itself"));
+	}
+	seen.push(ptr);
+
+	let index = state.abs_index(index);
+	let top = state.get_top();
+	let mut pairs = ~[];
+
+	state.push_nil();
+	while state.next(index) {
+		let kv = (value_at(state, -2, seen), value_at(state, -1, seen));
+		match kv {
+			(Ok(k), Ok(v)) => { pairs.push((k, v)); },
+			(Err(err), _) | (_, Err(err)) => {
+				state.set_top(top);
+				seen.pop();
+				return Err(err);
+			}
+		}
+		state.pop(1);
+	}
+	seen.pop();
+
+	let len = pairs.len();
+	let is_array = do pairs.iter().all |kv| {
+		match *kv {
+			(Number(n), _) => n >= 1.0 && n <= len as float && n == (n as int) as float,
+			_ => false
+		}
+	};
+	if !is_array {
+		return Ok(Table(pairs));
+	}
+
+	let mut items = vec::from_fn(len, |_| Nil);
+	for kv in pairs.move_iter() {
+		match kv {
+			(Number(n), v) => { items[n as uint - 1] = v; },
+			_ => {}
+		}
+	}
+	return Ok(Array(items));
+}
+
+/**
+ * A Rust type that can be moved into Lua as full userdata.
+ *
+ * Each type gets a metatable in the registry under `lua_type_name`, whose
+ * `__gc` drops the Rust value and whose `__index` holds the `lua_methods`, so
+ * they can be called as `obj:method(...)`. Methods receive the userdata as
+ * their first argument; see `lua_method!`.
+ */
+pub trait LuaUserData {
+	/// Registry name of the type's metatable. Must be unique per type.
+	fn lua_type_name(_: Option<Self>) -> &'static str;
+
+	/// Methods available on values of this type.
+	fn lua_methods(_: Option<Self>) -> ~[(&'static str, LuaCallback)] { ~[] }
+}
+
+// Layout of a Rust value inside a userdata block.
+struct UserDataCell<T> {
+	borrow: int, // 0 when free, -1 when borrowed by a UserDataRef, >0 while a RustFn runs
+	dropped: bool,
+	value: T
+}
+
+/**
+ * Exclusive access to a userdata value. The same value can't be borrowed
+ * again, or collected, until this goes out of scope.
+ */
+pub struct UserDataRef<'self, T> {
+	priv cell: &'self mut UserDataCell<T>,
+	// Keeps the userdata from being collected if its stack slot is popped
+	priv anchor: LuaRef
+}
+
+impl<'self, T> UserDataRef<'self, T> {
+	/// Get a reference to the value.
+	pub fn get<'a>(&'a self) -> &'a T {
+		&self.cell.value
+	}
+
+	/// Get a mutable reference to the value.
+	pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+		&mut self.cell.value
+	}
+}
+
+#[unsafe_destructor]
+impl<'self, T> Drop for UserDataRef<'self, T> {
+	fn drop(&mut self) {
+		self.cell.borrow = 0;
+	}
+}
+
+/// Move a value into Lua as full userdata and push it.
+pub fn push_userdata<T: LuaUserData>(state: &state::State, val: T) {
+	unsafe {
+		let p = state.new_userdata(sys::size_of::<UserDataCell<T>>()) as *mut UserDataCell<T>;
+		intrinsics::move_val_init(&mut *p, UserDataCell{ borrow: 0, dropped: false, value: val });
+	}
+
+	if state.new_metatable(LuaUserData::lua_type_name(None::<T>)) {
+		let gc_fn: extern "Rust" fn(&state::State) -> Option<~str> = gc_userdata::<T>;
+		state.push_light_userdata(unsafe { cast::transmute(gc_fn) });
+		state.push_closure(userdata_gc, 1);
+		state.set_field(-2, "__gc");
+
+		// Hide the metatable from scripts, so they can't reach __gc
+		state.push_str(LuaUserData::lua_type_name(None::<T>));
+		state.set_field(-2, "__metatable");
+
+		state.new_table();
+		for m in LuaUserData::lua_methods(None::<T>).iter() {
+			match *m {
+				(name, cb) => {
+					state.push_function(cb);
+					state.set_field(-2, name);
+				}
+			}
+		}
+		state.set_field(-2, "__index");
+	}
+	state.set_metatable(-2);
+}
+
+// Get the cell of the userdata of type T at a stack index, unless it's been dropped.
+fn userdata_cell<'a, T: LuaUserData>(state: &'a state::State, index: int) -> Option<&'a mut UserDataCell<T>> {
+	match state.index_type(index) {
+		state::TUserData => {},
+		_ => { return None; }
+	}
+	if !state.get_metatable(index) {
+		return None;
+	}
+	state.get_field(state::LUA_REGISTRYINDEX as int, LuaUserData::lua_type_name(None::<T>));
+	let same_type = state.raw_equal(-1, -2);
+	state.pop(2);
+
+	if !same_type {
+		return None;
+	}
+	let cell = unsafe { &mut *(state.to_userdata(index) as *mut UserDataCell<T>) };
+	if cell.dropped { None } else { Some(cell) }
+}
+
+/// Borrow the userdata of type T at a stack index, if it is one and isn't already borrowed.
+pub fn get_userdata<'a, T: LuaUserData>(state: &'a state::State, index: int) -> Option<UserDataRef<'a, T>> {
+	match userdata_cell::<T>(state, index) {
+		Some(cell) => {
+			if cell.borrow != 0 {
+				return None;
+			}
+			cell.borrow = -1;
+			Some(UserDataRef{ cell: cell, anchor: LuaRef::new(state, index) })
+		},
+		None => None
+	}
+}
+
+// Drop the value of the userdata at index 1. Returns an error message if it isn't a live T.
+fn gc_userdata<T: LuaUserData>(state: &state::State) -> Option<~str> {
+	let p = match userdata_cell::<T>(state, 1) {
+		Some(cell) => {
+			if cell.borrow != 0 {
+				return Some(fmt!("cannot collect %s while it is in use",
+					LuaUserData::lua_type_name(None::<T>)));
+			}
+			cell.dropped = true;
+			ptr::to_mut_unsafe_ptr(&mut cell.value)
+		},
+		None => {
+			return Some(fmt!("bad argument #1 to '__gc' (%s expected)",
+				LuaUserData::lua_type_name(None::<T>)));
+		}
+	};
+	unsafe {
+		let _val: T = ptr::read_and_zero_ptr(p);
+	}
+
+	// Without a metatable the collector won't finalize it again
+	state.push_nil();
+	state.set_metatable(1);
+	None
+}
+
+extern "C" fn userdata_gc(raw_state: *state::lua_State) -> c_int {
+	do state::with_state(raw_state) |state| {
+		let gc_fn: extern "Rust" fn(&state::State) -> Option<~str> = unsafe {
+			cast::transmute(state.to_userdata(state::upvalue_index(1)))
+		};
+		match gc_fn(state) {
+			Some(msg) => state.raise_error(msg),
+			None => 0
+		}
+	}
+}
+
+struct RustFn {
+	f: ~fn(&state::State) -> Result<int, ~str>
+}
+
+impl LuaUserData for RustFn {
+	fn lua_type_name(_: Option<RustFn>) -> &'static str { "apollo.RustFn" }
+}
+
+/// Push a Rust closure as a Lua function. See `Lua.push_fn()`.
+pub fn push_fn(state: &state::State, f: ~fn(&state::State) -> Result<int, ~str>) {
+	push_userdata(state, RustFn{ f: f });
+	state.push_closure(rust_fn_call, 1);
+}
+
+// Ends a shared borrow of a userdata cell, also when the borrower fails
+struct SharedBorrow<'self> {
+	borrow: &'self mut int
+}
+
+#[unsafe_destructor]
+impl<'self> Drop for SharedBorrow<'self> {
+	fn drop(&mut self) {
+		*self.borrow -= 1;
+	}
+}
+
+extern "C" fn rust_fn_call(raw_state: *state::lua_State) -> c_int {
+	do run_callback(raw_state, "closure") |state| {
+		match userdata_cell::<RustFn>(state, state::upvalue_index(1)) {
+			Some(cell) => {
+				// Shared, so the closure may call back into itself
+				cell.borrow += 1;
+				let _release = SharedBorrow{ borrow: &mut cell.borrow };
+				(cell.value.f)(state)
+			},
+			None => Err(~"attempt to call a dropped Rust closure")
+		}
+	}
+}
+
+/**
+ * A per-script global environment.
+ *
+ * Chunks loaded through a sandbox see their own globals table. Reads of
+ * names the script hasn't defined fall back to the base created by
+ * `Lua.new_sandbox()`, writes never reach it. Sandboxes created with `spawn`
+ * share the base but not their globals.
+ */
+pub struct Sandbox {
+	priv env: LuaTable,
+	priv base: LuaTable
+}
+
+impl Sandbox {
+	/// Create another sandbox with the same base and empty globals.
+	pub fn spawn(&self) -> Sandbox {
+		do state::with_state(self.base.table.state()) |state| {
+			Sandbox{ env: sandbox_env(state, &self.base), base: self.base.clone() }
+		}
+	}
+
+	/// The sandbox's own globals table.
+	pub fn globals(&self) -> LuaTable {
+		self.env.clone()
+	}
+
+	/// Set a global visible only to this sandbox.
+	pub fn set<V: LuaPush>(&self, name: &str, val: V) {
+		self.env.raw_set(name, val);
+	}
+
+	/**
+	 * Get a global as seen from inside the sandbox.
+	 *
+	 * Fails if the value is the wrong type.
+	 */
+	pub fn get<V: LuaTo>(&self, name: &str) -> V {
+		self.env.get(name)
+	}
+
+	/// Load a string as a chunk that runs in the sandbox.
+	pub fn try_load_str(&self, s: &str) -> Result<LuaFunction, LuaErr> {
+		do self.with_env |state| { state.try_load_str(s) }
+	}
+
+	/// Load a file as a chunk that runs in the sandbox.
+	pub fn try_load_file(&self, filename: &str) -> Result<LuaFunction, LuaErr> {
+		do self.with_env |state| { state.try_load_file(filename) }
+	}
+
+	/**
+	 * Run a string in the sandbox.
+	 *
+	 * Fails if the string doesn't parse or raises an error.
+	 */
+	pub fn do_str(&self, s: &str) {
+		match self.try_do_str(s) {
+			Err(err) => { fail!(fmt!("Sandbox.do_str() failed: %s", err.to_str())); },
+			_ => {}
+		}
+	}
+
+	pub fn try_do_str(&self, s: &str) -> Result<(), LuaErr> {
+		match self.try_load_str(s) {
+			Ok(f) => f.try_call(()),
+			Err(err) => Err(err)
+		}
+	}
+
+	pub fn try_do_file(&self, filename: &str) -> Result<(), LuaErr> {
+		match self.try_load_file(filename) {
+			Ok(f) => f.try_call(()),
+			Err(err) => Err(err)
+		}
+	}
+
+	/// Run a loader that pushes a chunk, then set the chunk's environment.
+	fn with_env(&self, load: &fn(&state::State) -> Result<(), LuaErr>) -> Result<LuaFunction, LuaErr> {
+		do state::with_state(self.env.table.state()) |state| {
+			match load(state) {
+				Ok(()) => {
+					self.env.lua_push(state);
+					state.set_fenv(-2);
+					let f: Result<LuaFunction, LuaErr> = LuaTo::try_lua_to(state, -1);
+					state.pop(1);
+					f
+				},
+				Err(err) => Err(err)
+			}
+		}
+	}
+}
+
+fn new_table(state: &state::State) -> LuaTable {
+	state.new_table();
+	let t = LuaTo::lua_to(state, -1);
+	state.pop(1);
+	t
+}
+
+/// Create a globals table that falls back to base.
+fn sandbox_env(state: &state::State, base: &LuaTable) -> LuaTable {
+	let env = new_table(state);
+	let mt = new_table(state);
+	mt.raw_set("__index", base.clone());
+	mt.raw_set("__metatable", "sandboxed");
+	env.set_metatable(&mt);
+	env.raw_set("_G", env.clone());
+	env
+}
+
+// Globals and libraries that can escape a sandbox or modify the tables it shares.
+static SANDBOX_UNSAFE: &'static [&'static str] = &[
+	"rawset", "getmetatable", "getfenv", "setfenv",
+	"load", "loadstring", "loadfile", "dofile", "require", "module",
+	"debug", "package", "ffi"
+];
+
+/// Wrap a table in an empty proxy that reads from it and refuses writes.
+fn read_only_table(state: &state::State, t: &LuaTable) -> LuaTable {
+	let newindex: LuaCallback = read_only_newindex;
+	let proxy = new_table(state);
+	let mt = new_table(state);
+	mt.raw_set("__index", t.clone());
+	mt.raw_set("__newindex", newindex);
+	mt.raw_set("__metatable", "read-only");
+	proxy.set_metatable(&mt);
+	proxy
+}
+
+extern "C" fn read_only_newindex(raw_state: *state::lua_State) -> c_int {
+	do state::with_state(raw_state) |state| {
+		{
+			let msg = match state.try_to_str(2) {
+				Ok(key) => fmt!("attempt to modify read-only field '%s'", key),
+				Err(_) => ~"attempt to modify a read-only table"
+			};
+			state.push_str(msg);
+		}
+		state.error()
 	}
 }
 
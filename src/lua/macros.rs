@@ -18,20 +18,43 @@ macro_rules! lua_struct(
 					state.raw_set(-3);
 					)+
 				}
+
+				fn try_lua_push(&self, state: &State) -> Result<(), ~str> {
+					state.new_table();
+
+					$(
+					state.push_str(stringify!($field));
+					match self.$field.try_lua_push(state) {
+						Ok(()) => {},
+						Err(msg) => { return Err(msg); }
+					}
+					state.raw_set(-3);
+					)+
+					Ok(())
+				}
 			}
 
 			impl LuaTo for $s {
-				fn lua_to(state: &State, index: int) -> $s {
-					$s {
+				fn try_lua_to(state: &State, index: int) -> Result<$s, LuaErr> {
+					match state.index_type(index) {
+						TTable => {},
+						t => { return Err(state.type_err("table", t)); }
+					}
+					let index = state.abs_index(index);
+
+					Ok($s {
 						$(
 						$field: {
 							state.get_field(index, stringify!($field));
-							let r = LuaTo::lua_to(state, index);
+							let r = LuaTo::try_lua_to(state, -1);
 							state.pop(1);
-							r
+							match r {
+								Ok(v) => v,
+								Err(err) => { return Err(err); }
+							}
 						},
 						)+
-					}
+					})
 				}
 			}
 		}
@@ -55,7 +78,7 @@ macro_rules! lua_fn(
 				_len += 1; 
 			 )*
 
-			_lua.state().pcall(_len, 1, 0);
+			_lua.state().pcall(_len, LuaTo::lua_size(None::<$rty>), 0);
 
 			let _ret: $rty = _lua.pop();
 			return _ret;
@@ -92,7 +115,7 @@ macro_rules! lua_fn(
 				_len += 1; 
 			 )*
 
-			_lua.state().pcall(_len, 1, 0);
+			_lua.state().pcall(_len, LuaTo::lua_size(None::<$rty>), 0);
 
 			let _ret: $rty = _lua.pop();
 			_lua.state().set_top(_top);
@@ -129,7 +152,7 @@ macro_rules! lua_fn(
 				_len += 1; 
 			 )*
 
-			$lua.state().pcall(_len, 1, 0);
+			$lua.state().pcall(_len, LuaTo::lua_size(None::<$rty>), 0);
 
 			let _ret: $rty = $lua.pop();
 			_ret
@@ -142,15 +165,113 @@ macro_rules! lua_cb(
 		$bl:block
 	) => (
 		extern "C" fn $func(_raw_state: *ffi::lua_State) -> c_int {
-			do lua::with_state(_raw_state) |state| {
+			do lua::run_callback(_raw_state, stringify!($func)) |state| {
 				let mut _i = 1;
 				$(
-					let $arg: $aty = LuaTo::lua_to(state, _i); _i += 1;
+					let $arg: $aty = match LuaTo::try_lua_to(state, _i) {
+						Ok(v) => v,
+						Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+					};
+					_i += 1;
 				)*
 				let _ret: $rty = {if true $bl else { fail!("lua_cb! impossible") } };
-				_ret.lua_push(state);
+				_ret.lua_return(state)
+			}
+		}
+	);
+	// Trailing arguments after `;` take their default value when missing or nil
+	($func:ident ( $($arg:ident: $aty:ty ),* ; $($darg:ident: $daty:ty = $def:expr ),+ ) -> $rty:ty
+		$bl:block
+	) => (
+		extern "C" fn $func(_raw_state: *ffi::lua_State) -> c_int {
+			do lua::run_callback(_raw_state, stringify!($func)) |state| {
+				let mut _i = 1;
+				$(
+					let $arg: $aty = match LuaTo::try_lua_to(state, _i) {
+						Ok(v) => v,
+						Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+					};
+					_i += 1;
+				)*
+				$(
+					let $darg: $daty = match state.index_type(_i) {
+						lua::TNone | lua::TNil => $def,
+						_ => match LuaTo::try_lua_to(state, _i) {
+							Ok(v) => v,
+							Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+						}
+					};
+					_i += 1;
+				)+
+				let _ret: $rty = {if true $bl else { fail!("lua_cb! impossible") } };
+				_ret.lua_return(state)
+			}
+		}
+	);
+)
+
+macro_rules! lua_method(
+	($func:ident ( $this:ident: &mut $sty:ty $(, $arg:ident: $aty:ty )* ) -> $rty:ty
+		$bl:block
+	) => (
+		extern "C" fn $func(_raw_state: *ffi::lua_State) -> c_int {
+			do lua::run_callback(_raw_state, stringify!($func)) |state| {
+				let mut _this = match lua::get_userdata::<$sty>(state, 1) {
+					Some(ud) => ud,
+					None => {
+						return Err(fmt!("bad argument #1 to '%s' (%s expected)",
+							stringify!($func), stringify!($sty)));
+					}
+				};
+				let $this: &mut $sty = _this.get_mut();
+				let mut _i = 2;
+				$(
+					let $arg: $aty = match LuaTo::try_lua_to(state, _i) {
+						Ok(v) => v,
+						Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+					};
+					_i += 1;
+				)*
+				let _ret: $rty = {if true $bl else { fail!("lua_method! impossible") } };
+				_ret.lua_return(state)
+			}
+		}
+	);
+	// Trailing arguments after `;` take their default value when missing or nil
+	($func:ident ( $this:ident: &mut $sty:ty $(, $arg:ident: $aty:ty )* ; $($darg:ident: $daty:ty = $def:expr ),+ ) -> $rty:ty
+		$bl:block
+	) => (
+		extern "C" fn $func(_raw_state: *ffi::lua_State) -> c_int {
+			do lua::run_callback(_raw_state, stringify!($func)) |state| {
+				let mut _this = match lua::get_userdata::<$sty>(state, 1) {
+					Some(ud) => ud,
+					None => {
+						return Err(fmt!("bad argument #1 to '%s' (%s expected)",
+							stringify!($func), stringify!($sty)));
+					}
+				};
+				let $this: &mut $sty = _this.get_mut();
+				let mut _i = 2;
+				$(
+					let $arg: $aty = match LuaTo::try_lua_to(state, _i) {
+						Ok(v) => v,
+						Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+					};
+					_i += 1;
+				)*
+				$(
+					let $darg: $daty = match state.index_type(_i) {
+						lua::TNone | lua::TNil => $def,
+						_ => match LuaTo::try_lua_to(state, _i) {
+							Ok(v) => v,
+							Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+						}
+					};
+					_i += 1;
+				)+
+				let _ret: $rty = {if true $bl else { fail!("lua_method! impossible") } };
+				_ret.lua_return(state)
 			}
-			return 1;
 		}
 	);
 )
@@ -158,15 +279,48 @@ macro_rules! lua_cb(
 macro_rules! lua_def(
 	($module:ident :: $func:ident ( $( $arg:ident: $aty:ty ),* ) -> $rty:ty as $cfunc:ident ) => ({
 		extern fn $cfunc(raw_state: *ffi::lua_State) -> c_int {
-			do lua::with_state(raw_state) |state| {
+			do lua::run_callback(raw_state, stringify!($func)) |state| {
 				let mut _i = 1;
 				$(
-					let $arg: $aty = LuaTo::lua_to(state, _i); _i += 1;
+					let $arg: $aty = match LuaTo::try_lua_to(state, _i) {
+						Ok(v) => v,
+						Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+					};
+					_i += 1;
 				)*
 
-				$func($($arg),*).lua_push(state);
+				let _ret: $rty = $func($($arg),*);
+				_ret.lua_return(state)
+			}
+		}
+		$module.def(stringify!($func), $cfunc);
+	});
+	// Trailing arguments after `;` take their default value when missing or nil
+	($module:ident :: $func:ident ( $( $arg:ident: $aty:ty ),* ; $( $darg:ident: $daty:ty = $def:expr ),+ ) -> $rty:ty as $cfunc:ident ) => ({
+		extern fn $cfunc(raw_state: *ffi::lua_State) -> c_int {
+			do lua::run_callback(raw_state, stringify!($func)) |state| {
+				let mut _i = 1;
+				$(
+					let $arg: $aty = match LuaTo::try_lua_to(state, _i) {
+						Ok(v) => v,
+						Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+					};
+					_i += 1;
+				)*
+				$(
+					let $darg: $daty = match state.index_type(_i) {
+						lua::TNone | lua::TNil => $def,
+						_ => match LuaTo::try_lua_to(state, _i) {
+							Ok(v) => v,
+							Err(err) => { return Err(lua::arg_error(_i, stringify!($func), err)); }
+						}
+					};
+					_i += 1;
+				)+
+
+				let _ret: $rty = $func($($arg,)* $($darg),+);
+				_ret.lua_return(state)
 			}
-			return 1;
 		}
 		$module.def(stringify!($func), $cfunc);
 	});
@@ -1,7 +1,10 @@
 extern mod extra;
-use std::libc::{c_int, c_double};
+use std::libc::{c_char, c_int, c_double, c_void, size_t};
 use std::str::raw;
-use std::ptr;
+use std::{cast, int, libc, ptr, str, vec};
+use std::unstable::atomics::{AtomicBool, SeqCst};
+use std::unstable::intrinsics;
+use std::unstable::sync::UnsafeArc;
 use std::c_str::ToCStr;
 pub use self::ffi::*;
 mod ffi;
@@ -13,20 +16,489 @@ struct State {
 #[fixed_stack_segment] #[inline(never)]
 pub fn NewState() -> State {
 	unsafe {
-		let state = ffi::luaL_newstate();
-		return State { state: state };
+		let state = State { state: ffi::luaL_newstate() };
+		state.init_owner();
+		return state;
 	}
 }
 
-pub fn with_state<'r>(raw: *ffi::lua_State, f: &'r fn(&State)) {
-	f(&State{ state: raw });
+/**
+ * Create a state whose allocations are tracked and capped at limit bytes.
+ *
+ * Allocations past the limit fail, which raises a `MemAlloc` error in the
+ * running script. Returns None if Lua can't create the state, which is always
+ * the case for LuaJIT on x64 unless it's built with GC64.
+ */
+#[fixed_stack_segment] #[inline(never)]
+pub fn NewLimitedState(limit: uint) -> Option<State> {
+	unsafe {
+		let stats: *mut MemStats = cast::transmute(~MemStats{ used: 0, peak: 0, limit: limit });
+		let state = ffi::lua_newstate(limited_alloc, stats as *c_void);
+		if state.is_null() {
+			let _stats: ~MemStats = cast::transmute(stats);
+			return None;
+		}
+		let state = State { state: state };
+		state.init_owner();
+		return Some(state);
+	}
+}
+
+/// Allocation counters kept by `limited_alloc`, in bytes.
+pub struct MemStats {
+	used: uint,
+	peak: uint,
+	limit: uint
+}
+
+/**
+ * The allocator behind `NewLimitedState`, for `lua_newstate` with a `MemStats`
+ * as its userdata. Growing past the limit fails, shrinking never does.
+ */
+pub extern "C" fn limited_alloc(ud: *c_void, p: *c_void, osize: size_t, nsize: size_t) -> *c_void {
+	unsafe {
+		let stats: &mut MemStats = cast::transmute(ud);
+		let old = if p.is_null() { 0 } else { osize as uint };
+
+		if nsize == 0 {
+			libc::free(p as *mut c_void);
+			stats.used -= old;
+			return ptr::null();
+		}
+
+		let used = stats.used - old + nsize as uint;
+		// Shrinking must never fail
+		if nsize as uint > old && used > stats.limit {
+			return ptr::null();
+		}
+
+		let new = libc::realloc(p as *mut c_void, nsize) as *c_void;
+		if !new.is_null() {
+			stats.used = used;
+			if used > stats.peak {
+				stats.peak = used;
+			}
+		}
+		return new;
+	}
+}
+
+static OWNER_KEY: &'static str = "apollo.owner";
+
+// Counts the holders of a state: its creator and every `LuaRef` into it.
+// The state is destroyed when the last one lets go.
+struct StateOwner {
+	main: *ffi::lua_State,
+	refs: uint
+}
+
+// Let go of a state, destroying it if this was the last holder.
+fn release(owner: *mut StateOwner) {
+	unsafe {
+		(*owner).refs -= 1;
+		if (*owner).refs == 0 {
+			with_state((*owner).main, |main| main.destroy());
+		}
+	}
+}
+
+/**
+ * A persistent reference to a Lua value, anchored in the registry.
+ *
+ * The value stays alive until the reference is dropped. The reference also
+ * keeps the state open, so it may outlive the `Lua` it came from.
+ */
+pub struct LuaRef {
+	priv state: *ffi::lua_State,
+	priv owner: *mut StateOwner,
+	priv key: int
+}
+
+impl LuaRef {
+	/// Anchor the value at a stack index in the registry.
+	pub fn new(state: &State, index: int) -> LuaRef {
+		state.push_value(index);
+		let owner = state.retain();
+		LuaRef {
+			// A coroutine may be collected before the reference, the main thread can't be
+			state: if owner.is_null() { state.state } else { unsafe { (*owner).main } },
+			owner: owner,
+			key: state.reference(ffi::LUA_REGISTRYINDEX as int)
+		}
+	}
+
+	/// Get the raw state the reference belongs to.
+	pub fn state(&self) -> *ffi::lua_State {
+		self.state
+	}
+}
+
+impl Clone for LuaRef {
+	fn clone(&self) -> LuaRef {
+		do with_state(self.state) |state| {
+			state.push_ref(self);
+			let r = LuaRef::new(state, -1);
+			state.pop(1);
+			r
+		}
+	}
+}
+
+#[unsafe_destructor]
+impl Drop for LuaRef {
+	fn drop(&mut self) {
+		let key = self.key;
+		if self.owner.is_null() {
+			// Not a state created by apollo, its creator handles its lifetime
+			do with_state(self.state) |state| {
+				state.unreference(ffi::LUA_REGISTRYINDEX as int, key);
+			}
+			return;
+		}
+		unsafe {
+			do with_state((*self.owner).main) |main| {
+				main.unreference(ffi::LUA_REGISTRYINDEX as int, key);
+			}
+		}
+		release(self.owner);
+	}
+}
+
+/// Execution limits for `State.try_pcall_limited()`. None means unlimited.
+pub struct ExecLimit {
+	/// Number of VM instructions the call may execute.
+	instructions: Option<uint>,
+	/// Wall-clock time the call may take, in milliseconds.
+	timeout_ms: Option<u64>
+}
+
+static LIMIT_HOOK_KEY: &'static str = "apollo.limit_hook";
+static INTERRUPT_KEY: &'static str = "apollo.interrupt";
+
+// Instructions between checks of the deadline and interrupt flag
+static HOOK_INTERVAL: uint = 1000;
+
+struct LimitHook {
+	remaining: Option<uint>,
+	deadline: Option<u64>,
+	tripped: Option<~str>,
+	// Instruction count the hook was last installed with
+	armed: uint
+}
+
+impl LimitHook {
+	fn interval(&self) -> uint {
+		match self.remaining {
+			Some(n) if n < HOOK_INTERVAL => if n == 0 { 1 } else { n },
+			_ => HOOK_INTERVAL
+		}
+	}
+
+	/// Account for an interval of instructions and get the error message if a limit was hit.
+	fn check(&mut self, ran: uint) -> Option<~str> {
+		match self.tripped {
+			Some(ref msg) => { return Some(msg.clone()); },
+			None => {}
+		}
+		match self.remaining {
+			Some(n) if n <= ran => {
+				self.tripped = Some(~"instruction limit exceeded");
+			},
+			Some(n) => { self.remaining = Some(n - ran); },
+			None => {}
+		}
+		match self.deadline {
+			Some(d) if self.tripped.is_none() && extra::time::precise_time_ns() >= d => {
+				self.tripped = Some(~"time limit exceeded");
+			},
+			_ => {}
+		}
+		self.tripped.clone()
+	}
+}
+
+/**
+ * Interrupts calls running on a state, from any task.
+ *
+ * Get one with `State.interrupt_handle()`.
+ */
+#[deriving(Clone)]
+pub struct InterruptHandle {
+	priv flag: UnsafeArc<AtomicBool>
+}
+
+impl InterruptHandle {
+	/**
+	 * Make the running call fail with an `Interrupted` error.
+	 *
+	 * Does nothing if no call is running when the interrupt is checked for.
+	 */
+	pub fn interrupt(&self) {
+		unsafe {
+			(*self.flag.get()).store(true, SeqCst);
+		}
+	}
+}
+
+struct InterruptState {
+	flag: UnsafeArc<AtomicBool>,
+	raised: bool,
+	// Protected calls and resumes running on the state
+	depth: uint
+}
+
+impl InterruptState {
+	fn check(&mut self) -> Option<~str> {
+		unsafe {
+			if !self.raised && (*self.flag.get()).load(SeqCst) {
+				(*self.flag.get()).store(false, SeqCst);
+				self.raised = true;
+			}
+		}
+		if self.raised { Some(~"interrupted") } else { None }
+	}
+}
+
+extern "C" fn exec_hook(raw: *ffi::lua_State, _: *ffi::lua_Debug) {
+	do with_state(raw) |state| {
+		let tripped = match state.check_interrupt() {
+			None => state.check_limit(),
+			interrupted => interrupted
+		};
+		match tripped {
+			Some(msg) => {
+				// Keep failing on every instruction so scripts can't pcall their way out
+				unsafe { ffi::lua_sethook(raw, exec_hook, ffi::LUA_MASKCOUNT, 1); }
+				state.raise_error(msg);
+			},
+			None => {
+				// The next interval may be shorter if few instructions remain
+				state.install_hook();
+			}
+		}
+	}
+}
+
+static TRACEBACK_KEY: &'static str = "apollo.traceback";
+static TRACEBACK_OFF_KEY: &'static str = "apollo.traceback_off";
+
+// Run by `try_get_table` and `try_set_table` under pcall, with the table at index 1
+extern "C" fn gettable_fn(raw: *ffi::lua_State) -> c_int {
+	do with_state(raw) |state| { state.get_table(1); }
+	return 1;
+}
+
+extern "C" fn settable_fn(raw: *ffi::lua_State) -> c_int {
+	do with_state(raw) |state| { state.set_table(1); }
+	return 0;
+}
+
+extern "C" fn err_tostring(raw: *ffi::lua_State) -> c_int {
+	do with_state(raw) |state| {
+		if state.call_meta(1, "__tostring") { 1 } else { 0 }
+	}
+}
+
+// Message handler installed by `try_pcall`, records the stack and returns the error unchanged
+extern "C" fn traceback_handler(raw: *ffi::lua_State) -> c_int {
+	do with_state(raw) |state| {
+		let frames = state.registry_ptr(TRACEBACK_KEY) as *mut ~[StackFrame];
+		if !frames.is_null() {
+			unsafe { *frames = state.traceback(1); }
+		}
+	}
+	return 1;
+}
+
+/// Get the pseudo-index of the ith upvalue of the running C function.
+pub fn upvalue_index(i: int) -> int {
+	ffi::LUA_GLOBALSINDEX as int - i
+}
+
+pub fn with_state<'r, T>(raw: *ffi::lua_State, f: &'r fn(&State) -> T) -> T {
+	f(&State{ state: raw })
 }
 
 impl State {
-	#[fixed_stack_segment] #[inline(never)]
+	/// Get the raw `lua_State` pointer wrapped by this state.
+	pub fn raw(&self) -> *ffi::lua_State {
+		self.state
+	}
+
+	/**
+	 * Close the state.
+	 *
+	 * If `LuaRef`s into the state are still alive, it's destroyed when the
+	 * last of them is dropped instead.
+	 */
 	pub fn close(&self) {
+		let owner = self.registry_ptr(OWNER_KEY) as *mut StateOwner;
+		if owner.is_null() {
+			self.destroy();
+		} else {
+			release(owner);
+		}
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	fn destroy(&self) {
 		unsafe {
+			let stats = self.mem_stats();
+			let interrupt = self.interrupt_state();
+			let owner = self.registry_ptr(OWNER_KEY) as *mut StateOwner;
 			ffi::lua_close(self.state);
+			match stats {
+				Some(p) => { let _stats: ~MemStats = cast::transmute(p); },
+				None => {}
+			}
+			if !interrupt.is_null() {
+				let _interrupt: ~InterruptState = cast::transmute(interrupt);
+			}
+			if !owner.is_null() {
+				let _owner: ~StateOwner = cast::transmute(owner);
+			}
+		}
+	}
+
+	// The creator of the state holds the first reference
+	fn init_owner(&self) {
+		let owner: *mut StateOwner = unsafe {
+			cast::transmute(~StateOwner{ main: self.state, refs: 1 })
+		};
+		self.set_registry_ptr(OWNER_KEY, owner as *c_void);
+	}
+
+	// Add a holder of the state, returns null for states not created by apollo
+	fn retain(&self) -> *mut StateOwner {
+		let owner = self.registry_ptr(OWNER_KEY) as *mut StateOwner;
+		if !owner.is_null() {
+			unsafe { (*owner).refs += 1; }
+		}
+		owner
+	}
+
+	/// Push the value a reference points to.
+	pub fn push_ref(&self, r: &LuaRef) {
+		self.raw_get_i(ffi::LUA_REGISTRYINDEX as int, r.key);
+	}
+
+	/// Bytes currently allocated, if the state was created with `NewLimitedState`.
+	pub fn memory_used(&self) -> Option<uint> {
+		match self.mem_stats() {
+			Some(p) => Some(unsafe { (*p).used }),
+			None => None
+		}
+	}
+
+	/// Most bytes ever allocated at once, if the state was created with `NewLimitedState`.
+	pub fn memory_peak(&self) -> Option<uint> {
+		match self.mem_stats() {
+			Some(p) => Some(unsafe { (*p).peak }),
+			None => None
+		}
+	}
+
+	/**
+	 * Change the allocation limit.
+	 *
+	 * Returns false if the state wasn't created with `NewLimitedState`.
+	 */
+	pub fn set_memory_limit(&self, limit: uint) -> bool {
+		match self.mem_stats() {
+			Some(p) => {
+				unsafe { (*p).limit = limit; }
+				true
+			},
+			None => false
+		}
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	fn mem_stats(&self) -> Option<*mut MemStats> {
+		unsafe {
+			let mut ud: *c_void = ptr::null();
+			let alloc: *c_void = cast::transmute(ffi::lua_getallocf(self.state, &mut ud));
+			let ours: *c_void = cast::transmute(limited_alloc);
+			if alloc == ours { Some(ud as *mut MemStats) } else { None }
+		}
+	}
+
+	/// Push a new thread (coroutine) sharing this state's globals and return it.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn new_thread(&self) -> State {
+		unsafe {
+			State { state: ffi::lua_newthread(self.state) }
+		}
+	}
+
+	/**
+	 * Start or resume this thread with nargs arguments from its stack.
+	 *
+	 * Returns 0 when the coroutine finished, `LUA_YIELD` when it yielded or an
+	 * error code.
+	 */
+	pub fn resume(&self, nargs: int) -> c_int {
+		match self.resume_call(nargs) {
+			(res, _) => res
+		}
+	}
+
+	/**
+	 * Start or resume this thread with nargs arguments from its stack.
+	 *
+	 * Returns true when the coroutine yielded and false when it finished. On
+	 * error the error is popped from the stack and returned, with the
+	 * coroutine's call stack if it's a runtime error.
+	 */
+	pub fn try_resume(&self, nargs: int) -> Result<bool, LuaErr> {
+		match self.resume_call(nargs) {
+			(0, _) => Ok(false),
+			(ffi::LUA_YIELD, _) => Ok(true),
+			(errn, interrupted) => {
+				// The dead coroutine's stack is left as it was when the error was raised
+				let traceback = self.traceback(0);
+				match self.pop_err(errn) {
+					Runtime(err) => {
+						let mut err = err;
+						err.traceback = traceback;
+						if interrupted { Err(Interrupted(err.msg)) } else { Err(Runtime(err)) }
+					},
+					err => if interrupted { Err(Interrupted(err.to_str())) } else { Err(err) }
+				}
+			}
+		}
+	}
+
+	// Resume, returns the result and whether an interrupt was raised during it.
+	#[fixed_stack_segment] #[inline(never)]
+	fn resume_call(&self, nargs: int) -> (c_int, bool) {
+		self.enter_call();
+		let res = unsafe {
+			ffi::lua_resume(self.state, nargs as c_int)
+		};
+		(res, self.leave_call())
+	}
+
+	/// Yield the running coroutine from a C function, use as `return state.yield_values(n)`.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn yield_values(&self, nresults: int) -> c_int {
+		unsafe {
+			ffi::lua_yield(self.state, nresults as c_int)
+		}
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn status(&self) -> c_int {
+		unsafe {
+			ffi::lua_status(self.state)
+		}
+	}
+
+	/// Pop n values from this state and push them onto another thread of the same state.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn xmove(&self, to: &State, n: int) {
+		unsafe {
+			ffi::lua_xmove(self.state, to.state, n as c_int);
 		}
 	}
 
@@ -37,6 +509,53 @@ impl State {
 		}
 	}
 
+	/**
+	 * Open a single standard library.
+	 *
+	 * `LibFfi` isn't registered as a global by LuaJIT itself, so it's set as the
+	 * global `ffi` here. Returns the error if the library fails to open.
+	 */
+	pub fn open_lib(&self, lib: LuaLib) -> Result<(), LuaErr> {
+		let (open, name): (ffi::LuaCallback, &str) = match lib {
+			LibBase    => (ffi::luaopen_base, ""),
+			LibPackage => (ffi::luaopen_package, "package"),
+			LibString  => (ffi::luaopen_string, "string"),
+			LibTable   => (ffi::luaopen_table, "table"),
+			LibMath    => (ffi::luaopen_math, "math"),
+			LibIo      => (ffi::luaopen_io, "io"),
+			LibOs      => (ffi::luaopen_os, "os"),
+			LibDebug   => (ffi::luaopen_debug, "debug"),
+			LibBit     => (ffi::luaopen_bit, "bit"),
+			LibJit     => (ffi::luaopen_jit, "jit"),
+			LibFfi     => (ffi::luaopen_ffi, "ffi")
+		};
+
+		self.push_function(open);
+		self.push_str(name);
+		match lib {
+			LibFfi => {
+				match self.try_pcall(1, 1, 0) {
+					Ok(()) => { self.set_global(name); Ok(()) },
+					Err(err) => Err(err)
+				}
+			},
+			_ => self.try_pcall(1, 0, 0)
+		}
+	}
+
+	/// Set each of the named globals to nil.
+	pub fn remove_globals(&self, names: &[&str]) {
+		for name in names.iter() {
+			self.push_nil();
+			self.set_global(*name);
+		}
+	}
+
+	/// Remove the base library functions that load code from files or strings.
+	pub fn strip_unsafe(&self) {
+		self.remove_globals(["dofile", "loadfile", "load", "loadstring"]);
+	}
+
 	#[fixed_stack_segment] #[inline(never)]
 	pub fn index_type(&self, index: int) -> LuaType {
 		unsafe {
@@ -64,7 +583,10 @@ impl State {
 			TBoolean       => fmt!("bool: %?", self.to_bool(index)),
 			TLightUserData => ~"light user data",
 			TNumber        => fmt!("number: %f", self.to_float(index)),
-			TString        => fmt!("string: %s", self.to_str(index)),
+			TString        => match self.try_to_str(index) {
+				Ok(s) => fmt!("string: %s", s),
+				Err(_) => fmt!("string: %u bytes, not UTF-8", self.to_bytes(index).len())
+			},
 			TTable         => ~"table",
 			TFunction      => ~"function",
 			TUserData      => ~"userdata",
@@ -73,17 +595,301 @@ impl State {
 		}
 	}
 
-	#[fixed_stack_segment] #[inline(never)]
+	/**
+	 * Call a function in protected mode.
+	 *
+	 * Fails if the call raises an error.
+	 */
 	pub fn pcall(&self, nargs: int, nresults: int, errfunci: int) {
+		match self.try_pcall(nargs, nresults, errfunci) {
+			Err(err) => { fail!(fmt!("pcall failed: %s", err.to_str())); },
+			_ => {}
+		}
+	}
+
+	/**
+	 * Call a function in protected mode.
+	 *
+	 * On error the error message is popped from the stack and returned. When
+	 * errfunci is 0 and tracebacks are on (see `set_traceback`), runtime errors
+	 * carry the call stack at the point of the error.
+	 */
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn try_pcall(&self, nargs: int, nresults: int, errfunci: int) -> Result<(), LuaErr> {
+		let traced = errfunci == 0 && self.traceback_enabled();
+		let func = self.get_top() - nargs;
+		let mut frames: ~[StackFrame] = ~[];
+		let outer = self.registry_ptr(TRACEBACK_KEY);
+
+		let errfunci = if traced {
+			self.push_function(traceback_handler);
+			self.insert(func);
+			self.set_registry_ptr(TRACEBACK_KEY, ptr::to_mut_unsafe_ptr(&mut frames) as *c_void);
+			func
+		} else {
+			errfunci
+		};
+
+		self.enter_call();
+		let err = unsafe {
+			self.maybe_err(ffi::lua_pcall(self.state,
+				nargs as c_int, nresults as c_int, errfunci as c_int))
+		};
+		let interrupted = self.leave_call();
+
+		if traced {
+			self.set_registry_ptr(TRACEBACK_KEY, outer);
+			self.remove(func);
+		}
+
+		match err {
+			Some(Runtime(err)) => {
+				let mut err = err;
+				err.traceback = frames;
+				if interrupted { Err(Interrupted(err.msg)) } else { Err(Runtime(err)) }
+			},
+			Some(err) => if interrupted { Err(Interrupted(err.to_str())) } else { Err(err) },
+			None => Ok(())
+		}
+	}
+
+	/// Turn tracebacks for errors raised in `try_pcall` on or off. They're on by default.
+	pub fn set_traceback(&self, enabled: bool) {
+		if enabled { self.push_nil(); } else { self.push_bool(true); }
+		self.set_field(ffi::LUA_REGISTRYINDEX as int, TRACEBACK_OFF_KEY);
+	}
+
+	fn traceback_enabled(&self) -> bool {
+		self.get_field(ffi::LUA_REGISTRYINDEX as int, TRACEBACK_OFF_KEY);
+		let enabled = match self.index_type(-1) {
+			TNil => true,
+			_ => false
+		};
+		self.pop(1);
+		enabled
+	}
+
+	/// Get the call stack of this state, starting level calls below the running function.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn traceback(&self, level: int) -> ~[StackFrame] {
+		let mut frames = ~[];
+		let mut level = level;
+		unsafe {
+			let mut ar: ffi::lua_Debug = intrinsics::init();
+			while ffi::lua_getstack(self.state, level as c_int, &mut ar) != 0 {
+				"Sln".with_c_str( |what| ffi::lua_getinfo(self.state, what, &mut ar) );
+				frames.push(StackFrame {
+					source: raw::from_c_str(ptr::to_unsafe_ptr(&ar.short_src[0])),
+					line: if ar.currentline > 0 { Some(ar.currentline as int) } else { None },
+					name: if ar.name.is_null() { None } else { Some(raw::from_c_str(ar.name)) },
+					what: raw::from_c_str(ar.what)
+				});
+				level += 1;
+			}
+		}
+		frames
+	}
+
+	/**
+	 * Like `try_pcall`, but abort the call with a `Timeout` error once it runs
+	 * out of instructions or time.
+	 *
+	 * Hooks don't run inside JIT-compiled code, so the JIT is turned off for
+	 * the duration of the call and back on afterwards if it was on before.
+	 */
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn try_pcall_limited(&self, nargs: int, nresults: int, errfunci: int, limit: &ExecLimit) -> Result<(), LuaErr> {
+		let mut hook = LimitHook {
+			remaining: limit.instructions,
+			deadline: match limit.timeout_ms {
+				Some(ms) => Some(extra::time::precise_time_ns() + ms * 1000000),
+				None => None
+			},
+			tripped: None,
+			armed: 0
+		};
+
+		let outer = self.limit_hook();
+		let jit_on = self.jit_enabled();
+
+		self.set_registry_ptr(LIMIT_HOOK_KEY, ptr::to_mut_unsafe_ptr(&mut hook) as *c_void);
 		unsafe {
-			let err = self.maybe_err(ffi::lua_pcall(self.state,
-				nargs as c_int, nresults as c_int, errfunci as c_int));
-			match err {
-				Some(msg) => {
-					fail!(fmt!("pcall failed: %s", msg.to_str()));
+			ffi::luaJIT_setmode(self.state, 0, ffi::LUAJIT_MODE_ENGINE | ffi::LUAJIT_MODE_OFF);
+		}
+		self.install_hook();
+
+		let res = self.try_pcall(nargs, nresults, errfunci);
+
+		self.set_registry_ptr(LIMIT_HOOK_KEY, outer as *c_void);
+		self.install_hook();
+		if jit_on && self.interrupt_state().is_null() {
+			unsafe {
+				ffi::luaJIT_setmode(self.state, 0, ffi::LUAJIT_MODE_ENGINE | ffi::LUAJIT_MODE_ON);
+			}
+		}
+
+		match (res, hook.tripped) {
+			(Err(_), Some(msg)) => Err(Timeout(msg)),
+			(res, _) => res
+		}
+	}
+
+	/**
+	 * Get a handle that can interrupt calls running on this state from any task.
+	 *
+	 * Installs a hook that checks for interrupts. Hooks don't run inside
+	 * JIT-compiled code, so this turns the JIT off for good.
+	 */
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn interrupt_handle(&self) -> InterruptHandle {
+		let mut p = self.interrupt_state();
+		if p.is_null() {
+			unsafe {
+				p = cast::transmute(~InterruptState{
+					flag: UnsafeArc::new(AtomicBool::new(false)),
+					raised: false,
+					depth: 0
+				});
+				ffi::luaJIT_setmode(self.state, 0, ffi::LUAJIT_MODE_ENGINE | ffi::LUAJIT_MODE_OFF);
+			}
+			self.set_registry_ptr(INTERRUPT_KEY, p as *c_void);
+			self.install_hook();
+		}
+		unsafe {
+			InterruptHandle{ flag: (*p).flag.clone() }
+		}
+	}
+
+	/**
+	 * Whether the JIT compiler is on, as reported by `jit.status()`. LuaJIT
+	 * starts with it on, so that's assumed if the jit library isn't loaded.
+	 */
+	fn jit_enabled(&self) -> bool {
+		let top = self.get_top();
+		self.push_value(ffi::LUA_REGISTRYINDEX as int);
+		for key in ["_LOADED", "jit", "status"].iter() {
+			match self.index_type(-1) {
+				TTable => {
+					self.push_str(*key);
+					self.raw_get(-2);
 				},
-				_ => {}
-			};
+				_ => {
+					self.set_top(top);
+					return true;
+				}
+			}
+		}
+
+		let on = match self.index_type(-1) {
+			TFunction => match self.try_pcall(0, 1, 0) {
+				Ok(()) => self.to_truthy(-1),
+				Err(_) => true
+			},
+			_ => true
+		};
+		self.set_top(top);
+		on
+	}
+
+	/// Get a pointer stored in the registry as light userdata, or null.
+	fn registry_ptr(&self, key: &str) -> *c_void {
+		self.get_field(ffi::LUA_REGISTRYINDEX as int, key);
+		let p = self.to_userdata(-1);
+		self.pop(1);
+		p
+	}
+
+	fn set_registry_ptr(&self, key: &str, p: *c_void) {
+		self.push_light_userdata(p);
+		self.set_field(ffi::LUA_REGISTRYINDEX as int, key);
+	}
+
+	fn limit_hook(&self) -> *mut LimitHook {
+		self.registry_ptr(LIMIT_HOOK_KEY) as *mut LimitHook
+	}
+
+	fn interrupt_state(&self) -> *mut InterruptState {
+		self.registry_ptr(INTERRUPT_KEY) as *mut InterruptState
+	}
+
+	fn check_limit(&self) -> Option<~str> {
+		let hook = self.limit_hook();
+		if hook.is_null() {
+			return None;
+		}
+		unsafe {
+			let ran = (*hook).armed;
+			(*hook).check(ran)
+		}
+	}
+
+	fn check_interrupt(&self) -> Option<~str> {
+		let interrupt = self.interrupt_state();
+		if interrupt.is_null() {
+			return None;
+		}
+		unsafe {
+			(*interrupt).check()
+		}
+	}
+
+	/// Note that a call is starting. The outermost call clears interrupts left over from before it.
+	fn enter_call(&self) {
+		let interrupt = self.interrupt_state();
+		if interrupt.is_null() {
+			return;
+		}
+		unsafe {
+			if (*interrupt).depth == 0 {
+				(*(*interrupt).flag.get()).store(false, SeqCst);
+				if (*interrupt).raised {
+					(*interrupt).raised = false;
+					self.install_hook();
+				}
+			}
+			(*interrupt).depth += 1;
+		}
+	}
+
+	/**
+	 * Note that a call has returned, returns true if it was interrupted. The
+	 * interrupt stays raised until the outermost call returns.
+	 */
+	fn leave_call(&self) -> bool {
+		let interrupt = self.interrupt_state();
+		if interrupt.is_null() {
+			return false;
+		}
+		unsafe {
+			// The handle may have been created while the call ran
+			if (*interrupt).depth > 0 {
+				(*interrupt).depth -= 1;
+			}
+			if !(*interrupt).raised {
+				return false;
+			}
+			if (*interrupt).depth == 0 {
+				(*interrupt).raised = false;
+				self.install_hook();
+			}
+		}
+		true
+	}
+
+	/// Set the count hook needed by the active limits and interrupt handle, if any.
+	#[fixed_stack_segment] #[inline(never)]
+	fn install_hook(&self) {
+		let limit = self.limit_hook();
+		let interrupt = self.interrupt_state();
+		unsafe {
+			if !limit.is_null() {
+				(*limit).armed = (*limit).interval();
+				ffi::lua_sethook(self.state, exec_hook, ffi::LUA_MASKCOUNT, (*limit).armed as c_int);
+			} else if !interrupt.is_null() {
+				ffi::lua_sethook(self.state, exec_hook, ffi::LUA_MASKCOUNT, HOOK_INTERVAL as c_int);
+			} else {
+				ffi::lua_sethook(self.state, exec_hook, 0, 0);
+			}
 		}
 	}
 
@@ -99,6 +905,41 @@ impl State {
 		}
 	}
 
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn get_table(&self, index: int) {
+		unsafe {
+			ffi::lua_gettable(self.state, index as c_int);
+		}
+	}
+
+	/**
+	 * Like `get_table`, but an error raised by a metamethod is returned
+	 * instead of propagated. The key is popped either way.
+	 */
+	pub fn try_get_table(&self, index: int) -> Result<(), LuaErr> {
+		let index = self.abs_index(index);
+		self.push_function(gettable_fn);
+		self.insert(-2);
+		self.push_value(index);
+		self.insert(-2);
+		self.try_pcall(2, 1, 0)
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn raw_get(&self, index: int) {
+		unsafe {
+			ffi::lua_rawget(self.state, index as c_int);
+		}
+	}
+
+	/// Get the length of a string or userdata, or the border of a table.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn obj_len(&self, index: int) -> uint {
+		unsafe {
+			ffi::lua_objlen(self.state, index as c_int) as uint
+		}
+	}
+
 	#[fixed_stack_segment] #[inline(never)]
 	pub fn get_top(&self) -> int {
 		unsafe {
@@ -106,14 +947,48 @@ impl State {
 		}
 	}
 
-	#[fixed_stack_segment] #[inline(never)]
+	/**
+	 * Load a file as a Lua chunk and push it on the stack as a function.
+	 *
+	 * Fails if the file can't be read or doesn't parse.
+	 */
 	pub fn load_file(&self, filename: &str) {
+		match self.try_load_file(filename) {
+			Err(err) => { fail!(fmt!("load_file failed: %s", err.to_str())); },
+			_ => {}
+		}
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn try_load_file(&self, filename: &str) -> Result<(), LuaErr> {
 		unsafe {
 			let cfname = filename.to_c_str();
-			let err = self.maybe_err(ffi::luaL_loadfile(self.state, cfname.unwrap()));
-			match err {
-				Some(msg) => { fail!(fmt!("load_file failed: %s", msg.to_str())); },
-				_ => {}
+			match self.maybe_err(ffi::luaL_loadfile(self.state, cfname.unwrap())) {
+				Some(err) => Err(err),
+				None => Ok(())
+			}
+		}
+	}
+
+	/**
+	 * Load a string as a Lua chunk and push it on the stack as a function.
+	 *
+	 * Fails if the string doesn't parse.
+	 */
+	pub fn load_str(&self, s: &str) {
+		match self.try_load_str(s) {
+			Err(err) => { fail!(fmt!("load_str failed: %s", err.to_str())); },
+			_ => {}
+		}
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn try_load_str(&self, s: &str) -> Result<(), LuaErr> {
+		unsafe {
+			let errn = s.with_c_str( |cs| ffi::luaL_loadstring(self.state, cs) );
+			match self.maybe_err(errn) {
+				Some(err) => Err(err),
+				None => Ok(())
 			}
 		}
 	}
@@ -123,13 +998,54 @@ impl State {
 		self.pcall(0, ffi::LUA_MULTRET as int, 0);
 	}
 
-	#[fixed_stack_segment] #[inline(never)]
+	pub fn try_do_file(&self, filename: &str) -> Result<(), LuaErr> {
+		match self.try_load_file(filename) {
+			Err(err) => Err(err),
+			Ok(()) => self.try_pcall(0, ffi::LUA_MULTRET as int, 0)
+		}
+	}
+
 	pub fn do_str(&self, s: &str) {
+		self.load_str(s);
+		self.pcall(0, ffi::LUA_MULTRET as int, 0);
+	}
+
+	pub fn try_do_str(&self, s: &str) -> Result<(), LuaErr> {
+		match self.try_load_str(s) {
+			Err(err) => Err(err),
+			Ok(()) => self.try_pcall(0, ffi::LUA_MULTRET as int, 0)
+		}
+	}
+
+	/// Convert a relative stack index into an absolute one. Pseudo-indices are returned as is.
+	pub fn abs_index(&self, index: int) -> int {
+		if index > 0 || index <= ffi::LUA_REGISTRYINDEX as int {
+			index
+		} else {
+			self.get_top() + index + 1
+		}
+	}
+
+	/// Push a copy of the value at index.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn push_value(&self, index: int) {
 		unsafe {
-			s.with_c_str( |cs| ffi::luaL_loadstring(self.state, cs) );
+			ffi::lua_pushvalue(self.state, index as c_int);
 		}
+	}
 
-		self.pcall(0, ffi::LUA_MULTRET as int, 0);
+	pub fn try_do_str_limited(&self, s: &str, limit: &ExecLimit) -> Result<(), LuaErr> {
+		match self.try_load_str(s) {
+			Err(err) => Err(err),
+			Ok(()) => self.try_pcall_limited(0, ffi::LUA_MULTRET as int, 0, limit)
+		}
+	}
+
+	pub fn try_do_file_limited(&self, filename: &str, limit: &ExecLimit) -> Result<(), LuaErr> {
+		match self.try_load_file(filename) {
+			Err(err) => Err(err),
+			Ok(()) => self.try_pcall_limited(0, ffi::LUA_MULTRET as int, 0, limit)
+		}
 	}
 
 	#[fixed_stack_segment] #[inline(never)]
@@ -150,6 +1066,84 @@ impl State {
 		}
 	}
 
+	/// Push a new block of raw memory as full userdata and return its address.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn new_userdata(&self, size: uint) -> *c_void {
+		unsafe {
+			ffi::lua_newuserdata(self.state, size as size_t)
+		}
+	}
+
+	/**
+	 * Push the registry metatable called name, creating it if it doesn't exist.
+	 *
+	 * Returns true if the metatable was created.
+	 */
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn new_metatable(&self, name: &str) -> bool {
+		unsafe {
+			name.with_c_str( |n| ffi::luaL_newmetatable(self.state, n) ) != 0
+		}
+	}
+
+	/// Push the metatable of the value at index. Pushes nothing and returns false if it has none.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn get_metatable(&self, index: int) -> bool {
+		unsafe {
+			ffi::lua_getmetatable(self.state, index as c_int) != 0
+		}
+	}
+
+	/// Pop a table and set it as the metatable of the value at index.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn set_metatable(&self, index: int) {
+		unsafe {
+			ffi::lua_setmetatable(self.state, index as c_int);
+		}
+	}
+
+	/**
+	 * Pop a table and set it as the environment of the function, thread or
+	 * userdata at index.
+	 *
+	 * Returns false if the value at index can't have an environment.
+	 */
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn set_fenv(&self, index: int) -> bool {
+		unsafe {
+			ffi::lua_setfenv(self.state, index as c_int) != 0
+		}
+	}
+
+	/**
+	 * Raise the value on top of the stack as a Lua error.
+	 *
+	 * Never returns, use as `return state.error()` at the end of a C function.
+	 * Rust values still alive in the calling frames are not dropped.
+	 */
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn error(&self) -> c_int {
+		unsafe {
+			ffi::lua_error(self.state)
+		}
+	}
+
+	/// Raise msg as a Lua error. Like `error()`, but frees the message first.
+	pub fn raise_error(&self, msg: ~str) -> c_int {
+		{
+			let msg = msg;
+			self.push_str(msg);
+		}
+		self.error()
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn raw_equal(&self, index1: int, index2: int) -> bool {
+		unsafe {
+			ffi::lua_rawequal(self.state, index1 as c_int, index2 as c_int) != 0
+		}
+	}
+
 	pub fn set_global(&self, name: &str) {
 		self.set_field(ffi::LUA_GLOBALSINDEX as int, name);
 	}
@@ -168,6 +1162,19 @@ impl State {
 		}
 	}
 
+	/**
+	 * Like `set_table`, but an error raised by a metamethod is returned
+	 * instead of propagated. The key and value are popped either way.
+	 */
+	pub fn try_set_table(&self, index: int) -> Result<(), LuaErr> {
+		let index = self.abs_index(index);
+		self.push_function(settable_fn);
+		self.insert(-3);
+		self.push_value(index);
+		self.insert(-3);
+		self.try_pcall(3, 0, 0)
+	}
+
 	#[fixed_stack_segment] #[inline(never)]
 	pub fn raw_set(&self, index: int) {
 		unsafe {
@@ -182,6 +1189,33 @@ impl State {
 		}
 	}
 	
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn raw_get_i(&self, index: int, n: int) {
+		unsafe {
+			ffi::lua_rawgeti(self.state, index as c_int, n as c_int);
+		}
+	}
+
+	/**
+	 * Pop the top value and store it in the table at index under a fresh integer key.
+	 *
+	 * Returns the key, or `LUA_REFNIL` if the value was nil.
+	 */
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn reference(&self, index: int) -> int {
+		unsafe {
+			ffi::luaL_ref(self.state, index as c_int) as int
+		}
+	}
+
+	/// Release a key created with `reference`.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn unreference(&self, index: int, reference: int) {
+		unsafe {
+			ffi::luaL_unref(self.state, index as c_int, reference as c_int);
+		}
+	}
+
 	#[fixed_stack_segment] #[inline(never)]
 	pub fn next(&self, index: int) -> bool {
 		unsafe {
@@ -207,75 +1241,183 @@ impl State {
 		}
 	}
 
-	#[fixed_stack_segment] #[inline(never)]
 	pub fn to_bool(&self, index: int) -> bool {
-		unsafe {
-			match self.index_type(index) {
-				TBoolean => {
-					return ffi::lua_toboolean(self.state, index as c_int) != 0
-				},
-				t => {
-					return fail!(fmt!("to_bool failed because stack has %s", t.to_str()));
-				}
-			};
+		match self.try_to_bool(index) {
+			Ok(b) => b,
+			Err(err) => fail!(fmt!("to_bool failed: %s", err.to_str()))
 		}
 	}
 
 	#[fixed_stack_segment] #[inline(never)]
-	pub fn to_int(&self, index: int) -> int {
+	pub fn try_to_bool(&self, index: int) -> Result<bool, LuaErr> {
 		unsafe {
 			match self.index_type(index) {
-				TNumber => {
-					return ffi::lua_tointeger(self.state, index as c_int) as int;
-				},
-				t => {
-					return fail!(fmt!("to_int failed because stack has %s", t.to_str()));
-				}
-			};
+				TBoolean => Ok(ffi::lua_toboolean(self.state, index as c_int) != 0),
+				t => Err(self.type_err("boolean", t))
+			}
 		}
 	}
 
+	/// Get the truthiness of a value: anything but nil and false is true.
 	#[fixed_stack_segment] #[inline(never)]
-	pub fn to_str(&self, index: int) -> ~str{
+	pub fn to_truthy(&self, index: int) -> bool {
 		unsafe {
-			match self.index_type(index) {
-				TString => {
-					let strPtr = ffi::lua_tolstring(self.state, index as c_int, ptr::null());
-					return raw::from_c_str(strPtr);
-				},
-				t => {
-					return fail!(fmt!("to_str failed because stack has %s", t.to_str()));
+			ffi::lua_toboolean(self.state, index as c_int) != 0
+		}
+	}
+
+	pub fn to_int(&self, index: int) -> int {
+		match self.try_to_int(index) {
+			Ok(i) => i,
+			Err(err) => fail!(fmt!("to_int failed: %s", err.to_str()))
+		}
+	}
+
+	/// Get a number, returning an error if it has a fractional part or doesn't fit an int.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn try_to_int(&self, index: int) -> Result<int, LuaErr> {
+		let min = int::min_value as float;
+		match self.try_to_integer(index, min, -min, "int") {
+			Ok(_) => unsafe { Ok(ffi::lua_tointeger(self.state, index as c_int) as int) },
+			Err(err) => Err(err)
+		}
+	}
+
+	/**
+	 * Get a number for conversion to the integer type named ty.
+	 *
+	 * Returns an error if the number has a fractional part or is outside
+	 * [min, limit). Bounds are floats so 64 bit limits stay exact.
+	 */
+	pub fn try_to_integer(&self, index: int, min: float, limit: float, ty: &str) -> Result<float, LuaErr> {
+		let n = match self.try_to_float(index) {
+			Ok(n) => n,
+			Err(err) => { return Err(err); }
+		};
+		if n.floor() != n {
+			return Err(runtime_err(~"number has no integer representation"));
+		}
+		if n < min || n >= limit {
+			return Err(runtime_err(fmt!("number out of range for %s", ty)));
+		}
+		Ok(n)
+	}
+
+	pub fn to_str(&self, index: int) -> ~str {
+		match self.try_to_str(index) {
+			Ok(s) => s,
+			Err(err) => fail!(fmt!("to_str failed: %s", err.to_str()))
+		}
+	}
+
+	/// Get a string, returning an error if it isn't one or isn't valid UTF-8.
+	pub fn try_to_str(&self, index: int) -> Result<~str, LuaErr> {
+		match self.try_to_bytes(index) {
+			Ok(bytes) => {
+				if str::is_utf8(bytes) {
+					Ok(str::from_utf8_owned(bytes))
+				} else {
+					Err(runtime_err(~"string is not valid UTF-8"))
 				}
-			}
+			},
+			Err(err) => Err(err)
+		}
+	}
+
+	pub fn to_bytes(&self, index: int) -> ~[u8] {
+		match self.try_to_bytes(index) {
+			Ok(b) => b,
+			Err(err) => fail!(fmt!("to_bytes failed: %s", err.to_str()))
+		}
+	}
+
+	/// Get the exact contents of a string, including any embedded zeros.
+	pub fn try_to_bytes(&self, index: int) -> Result<~[u8], LuaErr> {
+		match self.index_type(index) {
+			TString => unsafe {
+				let (p, len) = self.to_bytes_raw(index);
+				Ok(vec::raw::from_buf_raw(p, len))
+			},
+			t => Err(self.type_err("string", t))
 		}
 	}
 
+	/**
+	 * Get a pointer to the bytes of the string at index and its length, without copying.
+	 *
+	 * The bytes are followed by a zero. They stay valid only while the string is
+	 * reachable from Lua, and the value at index must be a string.
+	 */
 	#[fixed_stack_segment] #[inline(never)]
+	pub unsafe fn to_bytes_raw(&self, index: int) -> (*u8, uint) {
+		let mut len: size_t = 0;
+		let p = ffi::lua_tolstring(self.state, index as c_int,
+			ptr::to_mut_unsafe_ptr(&mut len) as *size_t);
+		(p as *u8, len as uint)
+	}
+
 	pub fn to_float(&self, index: int) -> float {
+		match self.try_to_float(index) {
+			Ok(f) => f,
+			Err(err) => fail!(fmt!("to_float failed: %s", err.to_str()))
+		}
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn try_to_float(&self, index: int) -> Result<float, LuaErr> {
 		unsafe {
 			match self.index_type(index) {
-				TNumber => {
-					return ffi::lua_tonumber(self.state, index as c_int) as float;
-				},
-				t => {
-					return fail!(fmt!("to_float failed because stack has %s", t.to_str()));
-				}
+				TNumber => Ok(ffi::lua_tonumber(self.state, index as c_int) as float),
+				t => Err(self.type_err("number", t))
 			}
 		}
 	}
 
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn to_userdata(&self, index: int) -> *c_void {
+		unsafe {
+			ffi::lua_touserdata(self.state, index as c_int)
+		}
+	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn to_thread(&self, index: int) -> State {
+		unsafe {
+			State { state: ffi::lua_tothread(self.state, index as c_int) }
+		}
+	}
+
+	/// Get the identity pointer of a table, function, userdata or thread.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn to_pointer(&self, index: int) -> *c_void {
+		unsafe {
+			ffi::lua_topointer(self.state, index as c_int)
+		}
+	}
+
+	/// Build the error returned when a stack value has an unexpected type.
+	pub fn type_err(&self, expected: &str, got: LuaType) -> LuaErr {
+		runtime_err(fmt!("%s expected, got %s", expected, got.to_str()))
+	}
+
 	pub fn maybe_err(&self, errn: c_int) -> Option<LuaErr> {
 		if errn == 0 { return None; }
 		return Some(self.pop_err(errn));
 	}
 
+	/// Pop the error object left on the stack by a failed call.
 	pub fn pop_err(&self, errn: c_int) -> LuaErr {
 		assert!(errn != 0);
 
-		let msg = self.to_str(-1);
+		let msg = self.err_msg(-1);
+		let value = match errn {
+			ffi::LUA_ERRRUN => Some(LuaRef::new(self, -1)),
+			_ => None
+		};
+		self.pop(1);
 		let err = match errn {
 			ffi::LUA_YIELD     => Yield(msg),
-			ffi::LUA_ERRRUN    => Runtime(msg),
+			ffi::LUA_ERRRUN    => Runtime(RuntimeError{ msg: msg, value: value, traceback: ~[] }),
 			ffi::LUA_ERRSYNTAX => Syntax(msg),
 			ffi::LUA_ERRMEM    => MemAlloc(msg),
 			ffi::LUA_ERRERR    => ErrFunc(msg),
@@ -284,17 +1426,61 @@ impl State {
 		return err;
 	}
 
+	/// Describe an error object: strings and numbers as-is, otherwise through its `__tostring`.
+	#[fixed_stack_segment] #[inline(never)]
+	fn err_msg(&self, index: int) -> ~str {
+		match self.index_type(index) {
+			TString => match self.try_to_str(index) {
+				Ok(msg) => { return msg; },
+				Err(_) => {}
+			},
+			TNumber => { return self.to_float(index).to_str(); },
+			_ => {}
+		}
+
+		// __tostring may raise an error itself, so call it in protected mode
+		let index = self.abs_index(index);
+		self.push_function(err_tostring);
+		self.push_value(index);
+		let msg = unsafe {
+			match ffi::lua_pcall(self.state, 1, 1, 0) {
+				0 => match self.index_type(-1) {
+					TString => Some(self.to_str(-1)),
+					_ => None
+				},
+				_ => None
+			}
+		};
+		self.pop(1);
+
+		match msg {
+			Some(msg) => msg,
+			None => fmt!("(error object is a %s value)", self.index_type(index).to_str())
+		}
+	}
+
+	/// Call metamethod `event` of the value at index, leaving its result on the stack.
+	/// Returns false and pushes nothing if there's no such metamethod.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn call_meta(&self, index: int, event: &str) -> bool {
+		unsafe {
+			do event.with_c_str |e| {
+				ffi::luaL_callmeta(self.state, index as c_int, e) != 0
+			}
+		}
+	}
+
 	#[fixed_stack_segment] #[inline(never)]
 	pub fn push_bool(&self, b: bool) {
 		unsafe {
-			ffi::lua_pushinteger(self.state, match b {true => 1, false => 0} as c_int);
+			ffi::lua_pushboolean(self.state, match b {true => 1, false => 0} as c_int);
 		}
 	}
 
 	#[fixed_stack_segment] #[inline(never)]
 	pub fn push_int(&self, integer: int) {
 		unsafe {
-			ffi::lua_pushinteger(self.state, integer as c_int);
+			ffi::lua_pushinteger(self.state, integer as ffi::lua_Integer);
 		}
 	}
 
@@ -305,10 +1491,17 @@ impl State {
 		}
 	}
 
-	#[fixed_stack_segment] #[inline(never)]
 	pub fn push_str(&self, s: &str) {
+		self.push_bytes(s.as_bytes());
+	}
+
+	/// Push bytes as a Lua string, embedded zeros included.
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn push_bytes(&self, bytes: &[u8]) {
 		unsafe {
-			s.with_c_str( |cs| ffi::lua_pushstring(self.state, cs));
+			do bytes.as_imm_buf |p, len| {
+				ffi::lua_pushlstring(self.state, p as *c_char, len as size_t);
+			}
 		}
 	}
 
@@ -332,6 +1525,27 @@ impl State {
 			ffi::lua_pushnil(self.state);
 		}
 	}
+
+	#[fixed_stack_segment] #[inline(never)]
+	pub fn push_light_userdata(&self, p: *c_void) {
+		unsafe {
+			ffi::lua_pushlightuserdata(self.state, p);
+		}
+	}
+}
+
+pub enum LuaLib {
+	LibBase,
+	LibPackage,
+	LibString,
+	LibTable,
+	LibMath,
+	LibIo,
+	LibOs,
+	LibDebug,
+	LibBit,
+	LibJit,
+	LibFfi
 }
 
 pub enum LuaType {
@@ -366,24 +1580,85 @@ impl ToStr for LuaType {
 	}
 }
 
+/// A function call on the Lua stack.
+#[deriving(Clone)]
+pub struct StackFrame {
+	/// Chunk the function was defined in, such as a file name.
+	source: ~str,
+	/// Line being executed, if known.
+	line: Option<int>,
+	/// Name of the function, if Lua could work it out.
+	name: Option<~str>,
+	/// "Lua", "C", "main" or "tail".
+	what: ~str
+}
+
+impl ToStr for StackFrame {
+	fn to_str(&self) -> ~str {
+		let location = match self.line {
+			Some(line) => fmt!("%s:%d", self.source, line),
+			None => self.source.clone()
+		};
+		match self.name {
+			Some(ref name) => fmt!("%s: in function '%s'", location, *name),
+			None if self.what.as_slice() == "main" => fmt!("%s: in main chunk", location),
+			None => fmt!("%s: in ?", location)
+		}
+	}
+}
+
+/// An error raised by running Lua code.
+#[deriving(Clone)]
+pub struct RuntimeError {
+	/// Best-effort description of the error value.
+	msg: ~str,
+	/// The value passed to `error()`, such as a table of error details. None if raised from Rust.
+	value: Option<LuaRef>,
+	/// Call stack where the error was raised, innermost call first. Empty if not collected.
+	traceback: ~[StackFrame]
+}
+
+impl ToStr for RuntimeError {
+	fn to_str(&self) -> ~str {
+		let mut s = self.msg.clone();
+		if !self.traceback.is_empty() {
+			s.push_str("\nstack traceback:");
+			for frame in self.traceback.iter() {
+				s.push_str("\n\t");
+				s.push_str(frame.to_str());
+			}
+		}
+		s
+	}
+}
+
+/// Build a `Runtime` error without a traceback.
+pub fn runtime_err(msg: ~str) -> LuaErr {
+	Runtime(RuntimeError{ msg: msg, value: None, traceback: ~[] })
+}
+
 pub enum LuaErr {
 	Yield(~str),
-	Runtime(~str),
+	Runtime(RuntimeError),
 	Syntax(~str),
 	MemAlloc(~str),
 	ErrFunc(~str),
+	Timeout(~str),
+	Interrupted(~str),
 	Unknown(~str)
 }
 
 impl ToStr for LuaErr {
 	fn to_str(&self) -> ~str {
 		match *self {
-			Yield(ref msg)    => fmt!("Lua yield error: %s", *msg),
-			Runtime(ref msg)  => fmt!("Lua runtime error: %s", *msg),
-			Syntax(ref msg)   => fmt!("Lua syntax error: %s", *msg),
-			MemAlloc(ref msg) => fmt!("Lua memory allocation error: %s", *msg),
-			ErrFunc(ref msg)  => fmt!("Lua error handler error: %s", *msg),
-			Unknown(ref msg)  => fmt!("Lua error: %s", *msg),
+			Yield(ref msg)       => fmt!("Lua yield error: %s", *msg),
+			Runtime(ref err)     => fmt!("Lua runtime error: %s", err.to_str()),
+			Syntax(ref msg)      => fmt!("Lua syntax error: %s", *msg),
+			MemAlloc(ref msg)    => fmt!("Lua memory allocation error: %s", *msg),
+			ErrFunc(ref msg)     => fmt!("Lua error handler error: %s", *msg),
+			Timeout(ref msg)     => fmt!("Lua timeout: %s", *msg),
+			Interrupted(ref msg) => fmt!("Lua interrupted: %s", *msg),
+			Unknown(ref msg)     => fmt!("Lua error: %s", *msg),
 		}
 	}
 }
@@ -1,6 +1,14 @@
-use std::hashmap::HashMap;
-use std::libc::{c_int};
+extern mod extra;
+use extra::container::Deque;
+use extra::ringbuf::RingBuf;
+use extra::treemap::{TreeMap, TreeSet};
+use std::hashmap::{HashMap, HashSet};
+use std::libc::{c_int, c_void};
 use std::local_data;
+use std::ptr;
+use std::comm::stream;
+use std::task;
+use std::f32;
 use lua::{LuaTo,LuaPush};
 mod macros;
 mod lua;
@@ -300,3 +308,1291 @@ fn test_lua_cb() {
 	assert!(result == (15.5 + 16.6 + 18.2));
 	assert!(lua.state().get_top() == 0);
 }
+
+#[test]
+fn test_try_errors() {
+	let lua = lua::New();
+
+	match lua.state().try_do_str("this is not lua") {
+		Err(lua::Syntax(_)) => {},
+		_ => { fail!("expected a syntax error") }
+	}
+	assert!(lua.state().get_top() == 0);
+
+	match lua.state().try_do_str("error('boom')") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("boom")) },
+		_ => { fail!("expected a runtime error") }
+	}
+	assert!(lua.state().get_top() == 0);
+
+	lua.push("not a number");
+	match lua.try_pop::<int>() {
+		Err(lua::Runtime(err)) => { assert!(err.msg == ~"number expected, got string") },
+		_ => { fail!("expected a conversion error") }
+	}
+	assert!(lua.state().get_top() == 0);
+
+	lua.push(12);
+	assert!(lua.try_table_iter::<int, int>(-1).is_err());
+	lua.state().pop(1);
+}
+
+#[test]
+fn test_lua_value() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.state().do_str("
+		value = { 1.5, 'two', true, nested = { flag = false } }
+		list = { 'a', 'b', 'c' }
+	");
+
+	lua.state().get_global("value");
+	let value: lua::LuaValue = lua.pop();
+	match value {
+		lua::Table(ref pairs) => { assert!(pairs.len() == 4); },
+		_ => { fail!("expected a table") }
+	}
+
+	lua.state().get_global("list");
+	match lua.pop::<lua::LuaValue>() {
+		lua::Array(items) => {
+			assert!(items.len() == 3);
+			match items[1] {
+				lua::String(ref s) => { assert!(*s == ~"b"); },
+				_ => { fail!("expected a string") }
+			}
+		},
+		_ => { fail!("expected an array") }
+	}
+
+	lua.push(value.clone());
+	lua.state().set_global("copy");
+	lua.state().do_str("assert(copy[2] == 'two' and copy[3] == true and copy.nested.flag == false)");
+
+	lua.push(lua::Nil);
+	match lua.pop::<lua::LuaValue>() {
+		lua::Nil => {},
+		_ => { fail!("expected nil") }
+	}
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_lua_ref() {
+	lua_fn!( call_it(f: lua::LuaRef, a: int) -> int )
+
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.state().do_str("
+		function call_it(f, a)
+			return f(a)
+		end
+		double = function(a) return a * 2 end
+	");
+
+	lua.state().get_global("double");
+	let double: lua::LuaRef = lua.pop();
+	lua.state().do_str("double = nil; collectgarbage()");
+	assert!(lua.state().get_top() == 0);
+
+	assert!(call_it(double.clone(), 21, &lua) == 42);
+	assert!(call_it(double, 4, &lua) == 8);
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_handles_outlive_lua() {
+	let (add, t) = {
+		let lua = lua::New();
+		lua.state().do_str("
+			function add(a, b) return a + b end
+			t = { x = 1 }
+		");
+		let add: lua::LuaFunction = lua.global("add");
+		let t: lua::LuaTable = lua.global("t");
+		(add, t)
+	};
+
+	// The state stays open until the last handle is dropped
+	assert!(add.call::<(int, int), int>((2, 3)) == 5);
+	assert!(t.get::<&str, int>("x") == 1);
+}
+
+#[test]
+fn test_lua_function() {
+	let lua = lua::New();
+	lua.state().do_str("
+		ops = {
+			add = function(a, b) return a + b end
+		}
+		function make_divmod()
+			return function(a, b) return math.floor(a / b), a % b end
+		end
+		function greet() return 'hello' end
+	");
+	lua.state().open_libs();
+
+	lua.state().get_global("ops");
+	lua.state().get_field(-1, "add");
+	let add: lua::LuaFunction = lua.pop();
+	lua.state().pop(1);
+	assert!(add.call::<(int, int), int>((20, 22)) == 42);
+
+	let make_divmod: lua::LuaFunction = lua.global("make_divmod");
+	let divmod: lua::LuaFunction = make_divmod.call(());
+	let (q, r): (int, int) = divmod.call((17, 5));
+	assert!(q == 3 && r == 2);
+
+	let greet: lua::LuaFunction = lua.global("greet");
+	assert!(greet.call::<(), ~str>(()) == ~"hello");
+
+	assert!(lua.try_global::<lua::LuaFunction>("ops").is_err());
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_lua_table() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.state().do_str("
+		config = { name = 'apollo', 'x', 'y', 'z' }
+		setmetatable(config, { __index = function(t, k) return 'default' end })
+	");
+
+	let config: lua::LuaTable = lua.global("config");
+	assert!(config.get::<&str, ~str>("name") == ~"apollo");
+	assert!(config.get::<&str, ~str>("missing") == ~"default");
+	assert!(!config.contains_key(4));
+	assert!(config.len() == 3);
+
+	config.set("size", 3);
+	config.raw_set(4, "w");
+	assert!(config.raw_get::<int, ~str>(4) == ~"w");
+	assert!(config.try_get::<&str, int>("name").is_err());
+
+	let seq: ~[~str] = config.sequence::<~str>().collect();
+	assert!(seq == ~[~"x", ~"y", ~"z", ~"w"]);
+
+	let mut n = 0;
+	for kv in config.pairs::<lua::LuaValue, lua::LuaValue>() {
+		match kv {
+			(lua::String(_), _) | (lua::Number(_), _) => { n += 1; },
+			_ => { fail!("unexpected key type") }
+		}
+	}
+	assert!(n == 6);
+
+	let t = lua.new_table();
+	t.set(1, 1.5);
+	assert!(t.len() == 1);
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_userdata() {
+	local_data_key!(key_counter_dropped: int);
+	local_data_key!(key_counter_drops: int);
+
+	struct Counter {
+		count: int
+	}
+
+	impl Drop for Counter {
+		fn drop(&mut self) {
+			local_data::set(key_counter_dropped, self.count);
+			let drops = local_data::get(key_counter_drops, |v| v.map_default(0, |n| **n));
+			local_data::set(key_counter_drops, drops + 1);
+		}
+	}
+
+	lua_method!(incr(this: &mut Counter, n: int) -> int {
+		this.count += n;
+		this.count
+	});
+
+	impl lua::LuaUserData for Counter {
+		fn lua_type_name(_: Option<Counter>) -> &'static str { "test.Counter" }
+
+		fn lua_methods(_: Option<Counter>) -> ~[(&'static str, lua::LuaCallback)] {
+			~[("incr", incr)]
+		}
+	}
+
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	lua.push_userdata(Counter{ count: 10 });
+	lua.state().set_global("counter");
+	lua.state().do_str("
+		counter:incr(5)
+		total = counter:incr(2)
+	");
+	assert!(lua.global::<int>("total") == 17);
+
+	lua.state().get_global("counter");
+	match lua.userdata::<Counter>(-1) {
+		Some(c) => {
+			assert!(c.get().count == 17);
+			// Already borrowed, so it can't be aliased
+			assert!(lua.userdata::<Counter>(-1).is_none());
+		},
+		None => { fail!("expected a Counter") }
+	}
+	assert!(lua.userdata::<Counter>(-1).is_some());
+
+	// The borrow keeps the userdata alive after its slot is popped
+	match lua.userdata::<Counter>(-1) {
+		Some(c) => {
+			lua.state().pop(1);
+			lua.state().do_str("counter = nil; collectgarbage()");
+			assert!(c.get().count == 17);
+		},
+		None => { fail!("expected a Counter") }
+	}
+
+	lua.push(12);
+	assert!(lua.userdata::<Counter>(-1).is_none());
+	lua.state().pop(1);
+
+	lua.state().do_str("counter = nil; collectgarbage()");
+	local_data::get(key_counter_dropped, |val| {
+		match val {
+			Some(v) => { assert!(*v == 17) },
+			None => { fail!("Counter was not dropped") }
+		}
+	});
+	assert!(lua.state().get_top() == 0);
+
+	// Scripts can't get at __gc, and calling it by hand drops the value only once
+	lua.push_userdata(Counter{ count: 3 });
+	lua.state().set_global("other");
+	lua.state().do_str("
+		hidden = getmetatable(other)
+		local gc = debug.getmetatable(other).__gc
+		nil_ok = pcall(gc, nil)
+		first_ok = pcall(gc, other)
+		second_ok = pcall(gc, other)
+		method_ok = pcall(function() return other:incr(1) end)
+	");
+	assert!(lua.global::<~str>("hidden") == ~"test.Counter");
+	assert!(!lua.global::<bool>("nil_ok"));
+	assert!(lua.global::<bool>("first_ok"));
+	assert!(!lua.global::<bool>("second_ok"));
+	assert!(!lua.global::<bool>("method_ok"));
+
+	lua.state().do_str("other = nil; collectgarbage()");
+	local_data::get(key_counter_drops, |val| {
+		assert!(val.map_default(0, |n| **n) == 2);
+	});
+}
+
+#[test]
+fn test_push_fn() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	let (port, chan) = stream::<~str>();
+	let greeting = ~"hello, ";
+	lua.push_fn(|state| {
+		chan.send(greeting + state.to_str(1));
+		Ok(0)
+	});
+	lua.state().set_global("greet");
+
+	lua.module("host", |host| {
+		let offset = 100;
+		host.def_fn("offset", |state| {
+			state.push_int(state.to_int(1) + offset);
+			Ok(1)
+		});
+	});
+
+	lua.state().do_str("
+		greet('lua')
+		result = host.offset(23)
+	");
+	assert!(port.recv() == ~"hello, lua");
+	assert!(lua.global::<int>("result") == 123);
+
+	lua.push_fn(|_| Err(~"not allowed"));
+	lua.state().set_global("deny");
+	match lua.state().try_do_str("deny()") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("not allowed")); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	lua.state().do_str("greet = nil; host = nil; collectgarbage()");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_lua_thread() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.state().do_str("
+		function counter(from, to)
+			for i = from, to do
+				local step = coroutine.yield(i)
+				if step == 'stop' then return 'stopped' end
+			end
+			return 'done'
+		end
+		function broken() error('broken coroutine') end
+	");
+
+	let counter: lua::LuaFunction = lua.global("counter");
+	let co = lua.new_thread(&counter);
+
+	match co.resume((1, 3)) {
+		lua::Yielded([lua::Number(n)]) => { assert!(n == 1.0); },
+		_ => { fail!("expected a yield") }
+	}
+	match co.resume(()) {
+		lua::Yielded([lua::Number(n)]) => { assert!(n == 2.0); },
+		_ => { fail!("expected a yield") }
+	}
+	match co.resume("stop") {
+		lua::Finished([lua::String(ref s)]) => { assert!(*s == ~"stopped"); },
+		_ => { fail!("expected the coroutine to finish") }
+	}
+	match co.status() {
+		lua::Dead => {},
+		_ => { fail!("expected a dead coroutine") }
+	}
+	match co.resume(()) {
+		lua::Error(_) => {},
+		_ => { fail!("resuming a dead coroutine should fail") }
+	}
+
+	let broken: lua::LuaFunction = lua.global("broken");
+	let co = lua.new_thread(&broken);
+	match co.resume(()) {
+		lua::Error(lua::Runtime(err)) => { assert!(err.msg.contains("broken coroutine")); },
+		_ => { fail!("expected an error") }
+	}
+	match co.status() {
+		lua::Errored => {},
+		_ => { fail!("expected an errored coroutine") }
+	}
+
+	// Binary strings are kept as bytes, values that can't be converted are an error
+	lua.state().do_str("
+		function odd_values()
+			coroutine.yield('\\255')
+			coroutine.yield(_G)
+			return 'ok'
+		end
+	");
+	let odd_values: lua::LuaFunction = lua.global("odd_values");
+	let co = lua.new_thread(&odd_values);
+	match co.resume(()) {
+		lua::Yielded([lua::Binary(ref b)]) => { assert!(*b == ~[255u8]); },
+		_ => { fail!("expected a binary string") }
+	}
+	match co.resume(()) {
+		lua::Error(_) => {},
+		_ => { fail!("expected a cyclic table to be an error") }
+	}
+	match co.resume(()) {
+		lua::Finished([lua::String(ref s)]) => { assert!(*s == ~"ok"); },
+		_ => { fail!("expected the coroutine to finish") }
+	}
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_sandbox() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	let plugin_a = lua.new_sandbox(["type", "pcall", "string", "os.time"]);
+	let plugin_b = plugin_a.spawn();
+
+	plugin_a.set("name", "a");
+	plugin_a.do_str("
+		function hello() return 'hello from ' .. name end
+		has_io = io and 'yes' or 'no'
+		has_clock = os.clock and 'yes' or 'no'
+		has_time = type(os.time) == 'function' and 'yes' or 'no'
+		upper = string.upper('x')
+	");
+	assert!(plugin_a.get::<~str>("upper") == ~"X");
+	assert!(plugin_a.get::<~str>("has_io") == ~"no");
+	assert!(plugin_a.get::<~str>("has_clock") == ~"no");
+	assert!(plugin_a.get::<~str>("has_time") == ~"yes");
+
+	plugin_b.do_str("
+		ok = pcall(function() string.upper = nil end) and 'yes' or 'no'
+		sees_hello = hello and 'yes' or 'no'
+	");
+	assert!(plugin_b.get::<~str>("ok") == ~"no");
+	assert!(plugin_b.get::<~str>("sees_hello") == ~"no");
+
+	let hello: lua::LuaFunction = plugin_a.get("hello");
+	assert!(hello.call::<(), ~str>(()) == ~"hello from a");
+
+	lua.state().do_str("assert(hello == nil and has_io == nil and string.upper ~= nil)");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+#[should_fail]
+fn test_sandbox_rejects_rawset() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.new_sandbox(["string", "rawset"]);
+}
+
+#[test]
+#[should_fail]
+fn test_sandbox_rejects_debug() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.new_sandbox(["debug.getmetatable"]);
+}
+
+#[test]
+#[should_fail]
+fn test_sandbox_rejects_package() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.new_sandbox(["package"]);
+}
+
+#[test]
+#[should_fail]
+fn test_sandbox_rejects_package_loaded() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.new_sandbox(["package.loaded"]);
+}
+
+#[test]
+#[should_fail]
+fn test_sandbox_rejects_module() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.new_sandbox(["module"]);
+}
+
+#[test]
+#[should_fail]
+fn test_sandbox_rejects_ffi() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.new_sandbox(["ffi.C"]);
+}
+
+#[test]
+fn test_open_lib() {
+	let lua = lua::New();
+	assert!(lua.state().open_lib(lua::LibBase).is_ok());
+	assert!(lua.state().open_lib(lua::LibString).is_ok());
+	assert!(lua.state().open_lib(lua::LibTable).is_ok());
+	lua.state().strip_unsafe();
+
+	lua.state().do_str("
+		assert(string.rep('ab', 2) == 'abab')
+		assert(table.concat({ 'a', 'b' }) == 'ab')
+		assert(io == nil and os == nil and debug == nil and math == nil)
+		assert(dofile == nil and loadfile == nil and load == nil and loadstring == nil)
+		assert(print ~= nil)
+	");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_limited_alloc() {
+	let mut stats = lua::MemStats{ used: 0, peak: 0, limit: 100 };
+	let ud = ptr::to_mut_unsafe_ptr(&mut stats) as *c_void;
+
+	let a = lua::limited_alloc(ud, ptr::null(), 0, 60);
+	assert!(!a.is_null() && stats.used == 60);
+
+	// Growing past the limit fails and leaves the block as it was
+	assert!(lua::limited_alloc(ud, a, 60, 120).is_null());
+	assert!(stats.used == 60);
+
+	let b = lua::limited_alloc(ud, ptr::null(), 0, 40);
+	assert!(!b.is_null() && stats.used == 100 && stats.peak == 100);
+	assert!(lua::limited_alloc(ud, ptr::null(), 0, 1).is_null());
+
+	// Shrinking never fails, even over the limit
+	stats.limit = 10;
+	let a = lua::limited_alloc(ud, a, 60, 20);
+	assert!(!a.is_null() && stats.used == 60);
+
+	assert!(lua::limited_alloc(ud, a, 20, 0).is_null());
+	assert!(lua::limited_alloc(ud, b, 40, 0).is_null());
+	assert!(stats.used == 0 && stats.peak == 100);
+}
+
+#[test]
+fn test_memory_limit() {
+	let limit = 512 * 1024;
+	let lua = match lua::NewLimited(limit) {
+		Some(lua) => lua,
+		// LuaJIT on x64 without GC64 doesn't support custom allocators,
+		// test_limited_alloc covers the accounting on its own
+		None => { return; }
+	};
+	assert!(lua::New().state().memory_used().is_none());
+
+	let before = lua.state().memory_used().unwrap();
+	assert!(before > 0 && before <= limit);
+
+	match lua.state().try_do_str("
+		local t = {}
+		for i = 1, 1e7 do t[i] = i end
+	") {
+		Err(lua::MemAlloc(_)) => {},
+		_ => { fail!("expected a memory error") }
+	}
+	assert!(lua.state().memory_peak().unwrap() <= limit);
+
+	lua.state().do_str("x = 1 + 1");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_exec_limit() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	let budget = lua::ExecLimit{ instructions: Some(100000), timeout_ms: None };
+	match lua.state().try_do_str_limited("while true do end", &budget) {
+		Err(lua::Timeout(_)) => {},
+		_ => { fail!("expected the instruction limit to be hit") }
+	}
+
+	let deadline = lua::ExecLimit{ instructions: None, timeout_ms: Some(50) };
+	match lua.state().try_do_str_limited("
+		while true do pcall(function() while true do end end) end
+	", &deadline) {
+		Err(lua::Timeout(_)) => {},
+		_ => { fail!("expected the time limit to be hit") }
+	}
+
+	assert!(lua.state().try_do_str_limited("x = 1 + 1", &budget).is_ok());
+	match lua.state().try_do_str_limited("error('plain')", &budget) {
+		Err(lua::Runtime(_)) => {},
+		_ => { fail!("expected a runtime error") }
+	}
+
+	lua.state().do_str("for i = 1, 1000000 do end");
+	assert!(lua.state().get_top() == 0);
+
+	// A budget that isn't a multiple of the check interval isn't rounded up
+	let count_to = |n: uint| -> int {
+		let limit = lua::ExecLimit{ instructions: Some(n), timeout_ms: None };
+		assert!(lua.state().try_do_str_limited("n = 0 while true do n = n + 1 end", &limit).is_err());
+		lua.global::<int>("n")
+	};
+	let short = count_to(1000);
+	let long = count_to(2500);
+	assert!(long * 4 < short * 11);
+
+	// The JIT is left the way the host had it
+	lua.state().do_str("assert(jit.status())");
+	lua.state().do_str("jit.off()");
+	assert!(lua.state().try_do_str_limited("x = 1", &budget).is_ok());
+	lua.state().do_str("assert(not jit.status()) jit.on()");
+	assert!(lua.state().try_do_str_limited("x = 1", &budget).is_ok());
+	lua.state().do_str("assert(jit.status())");
+}
+
+#[test]
+fn test_interrupt() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	// Interrupting while nothing runs doesn't affect the next call
+	let handle = lua.state().interrupt_handle();
+	handle.interrupt();
+	lua.state().do_str("x = 1");
+
+	// Interrupt a running script from another task
+	let (port, chan) = stream::<()>();
+	let remote = handle.clone();
+	do task::spawn_sched(task::SingleThreaded) {
+		port.recv();
+		remote.interrupt();
+	}
+	lua.push_fn(|_| { chan.send(()); Ok(0) });
+	lua.state().set_global("started");
+	match lua.state().try_do_str("started() while true do end") {
+		Err(lua::Interrupted(_)) => {},
+		_ => { fail!("expected the script to be interrupted") }
+	}
+
+	// Interrupt from a callback while the script runs, pcall can't catch it
+	let stop = handle.clone();
+	lua.push_fn(|_| { stop.interrupt(); Ok(0) });
+	lua.state().set_global("stop");
+	match lua.state().try_do_str("
+		stop()
+		while true do pcall(function() while true do end end) end
+	") {
+		Err(lua::Interrupted(_)) => {},
+		_ => { fail!("expected the script to be interrupted") }
+	}
+
+	// Interrupting a coroutine doesn't leak into the next call
+	lua.state().do_str("function spin() stop() while true do end end");
+	let spin: lua::LuaFunction = lua.global("spin");
+	match lua.new_thread(&spin).resume(()) {
+		lua::Error(lua::Interrupted(_)) => {},
+		_ => { fail!("expected the coroutine to be interrupted") }
+	}
+
+	lua.state().do_str("x = 1 + 1");
+	assert!(lua.global::<int>("x") == 2);
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_traceback() {
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.state().load_str("
+		function inner()
+			error('deep error')
+		end
+		function outer()
+			inner()
+		end
+	");
+	lua.state().pcall(0, 0, 0);
+
+	let outer: lua::LuaFunction = lua.global("outer");
+	match outer.try_call::<(), ()>(()) {
+		Err(lua::Runtime(err)) => {
+			assert!(err.msg.contains("deep error"));
+			let names: ~[~str] = err.traceback.iter().filter_map(|f| f.name.clone()).collect();
+			assert!(names.contains(&~"inner"));
+			assert!(names.contains(&~"outer"));
+			assert!(err.traceback.iter().any(|f| f.line == Some(3)));
+			assert!(err.to_str().contains("stack traceback:"));
+		},
+		_ => { fail!("expected a runtime error") }
+	}
+
+	lua.state().set_traceback(false);
+	match outer.try_call::<(), ()>(()) {
+		Err(lua::Runtime(err)) => { assert!(err.traceback.is_empty()); },
+		_ => { fail!("expected a runtime error") }
+	}
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_error_values() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	match lua.state().try_do_str("error({code = 42}, 0)") {
+		Err(lua::Runtime(err)) => {
+			assert!(err.msg == ~"(error object is a table value)");
+			match err.value.unwrap().get::<lua::LuaValue>() {
+				Ok(lua::Table(ref pairs)) => {
+					assert!(pairs.len() == 1);
+					match pairs[0] {
+						(lua::String(ref k), lua::Number(v)) => { assert!(*k == ~"code" && v == 42.0); },
+						_ => { fail!("expected code = 42") }
+					}
+				},
+				_ => { fail!("expected a table error value") }
+			}
+		},
+		_ => { fail!("expected a runtime error") }
+	}
+	assert!(lua.state().get_top() == 0);
+
+	match lua.state().try_do_str("
+		error(setmetatable({}, { __tostring = function() return 'custom error' end }))
+	") {
+		Err(lua::Runtime(err)) => { assert!(err.msg == ~"custom error"); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	match lua.state().try_do_str("
+		error(setmetatable({}, { __tostring = function() error('nested') end }))
+	") {
+		Err(lua::Runtime(err)) => { assert!(err.msg == ~"(error object is a table value)"); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	match lua.state().try_do_str("error('plain', 0)") {
+		Err(lua::Runtime(err)) => {
+			match err.value.unwrap().get::<lua::LuaValue>() {
+				Ok(lua::String(ref s)) => { assert!(*s == ~"plain"); },
+				_ => { fail!("expected a string error value") }
+			}
+		},
+		_ => { fail!("expected a runtime error") }
+	}
+
+	// The error value keeps its state alive after the Lua is gone
+	let err = match lua::New().state().try_do_str("error(setmetatable({}, { __index = function() return 7 end }))") {
+		Err(lua::Runtime(err)) => err,
+		_ => { fail!("expected a runtime error") }
+	};
+	let value: lua::LuaTable = err.value.unwrap().get().unwrap();
+	assert!(value.get::<&str, int>("anything") == 7);
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_callback_errors() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	lua_cb!(add(a: float, b: float) -> float {
+		a + b
+	});
+
+	lua_cb!(checked_div(a: int, b: int) -> Result<int, ~str> {
+		if b == 0 { Err(~"division by zero") } else { Ok(a / b) }
+	});
+
+	lua_cb!(explode(msg: ~str) -> int {
+		fail!(msg)
+	});
+
+	lua_cb!(lookup(t: lua::LuaTable, key: ~str) -> Result<int, ~str> {
+		match t.try_get::<&str, int>(key) {
+			Ok(v) => Ok(v),
+			Err(lua::Runtime(err)) => Err(err.msg),
+			Err(err) => Err(err.to_str())
+		}
+	});
+
+	lua.state().push_function(add);
+	lua.state().set_global("add");
+	lua.state().push_function(checked_div);
+	lua.state().set_global("checked_div");
+	lua.state().push_function(explode);
+	lua.state().set_global("explode");
+	lua.state().push_function(lookup);
+	lua.state().set_global("lookup");
+
+	match lua.state().try_do_str("add(1, 'x')") {
+		Err(lua::Runtime(err)) => {
+			assert!(err.msg.contains("bad argument #2 to 'add' (number expected, got string)"));
+		},
+		_ => { fail!("expected a runtime error") }
+	}
+
+	lua.state().do_str("q = checked_div(12, 4)");
+	assert!(lua.global::<int>("q") == 3);
+	match lua.state().try_do_str("checked_div(1, 0)") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("division by zero")); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	// A metamethod error inside the callback comes back as a value, not a longjmp
+	match lua.state().try_do_str("
+		lookup(setmetatable({}, { __index = function(_, k) error('no field ' .. k, 0) end }), 'x')
+	") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("no field x")); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	match lua.state().try_do_str("explode('oops')") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("error in 'explode'")); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	// Failing conversions inside closures are contained too
+	lua.push_fn(|state| {
+		state.push_int(state.to_int(1) + 1);
+		Ok(1)
+	});
+	lua.state().set_global("incr");
+	match lua.state().try_do_str("incr('x')") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("Rust callback failed")); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	// The state is still usable after a callback error
+	lua.state().do_str("sum = add(1, 2)");
+	assert!(lua.global::<float>("sum") == 3.0);
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_multiple_returns() {
+	lua_fn!( triple(n: int) -> (int, ~str, float) );
+	lua_fn!( stats.minmax(a: int, b: int) -> (int, int) );
+
+	let lua = lua::New();
+	lua.state().do_str("
+		function triple(n)
+			return n, 'n=' .. n, n / 2
+		end
+		stats = {
+			minmax = function(a, b)
+				if a < b then return a, b else return b, a end
+			end
+		}
+	");
+
+	let (n, s, f) = triple(7, &lua);
+	assert!(n == 7 && s == ~"n=7" && f == 3.5);
+	assert!(minmax(9, 4, &lua) == (4, 9));
+	assert!(lua.state().get_top() == 0);
+
+	lua.state().do_str("function twelve() return 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 'twelve' end");
+	let f: lua::LuaFunction = lua.global("twelve");
+	let r: (int, int, int, int, int, int, int, int, int, int, int, ~str) = f.call(());
+	match r {
+		(a, _, _, _, _, _, _, _, _, _, k, ref l) => { assert!(a == 1 && k == 11 && *l == ~"twelve"); }
+	}
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_callback_returns() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	lua_cb!(nothing(a: int) -> () {
+		let _ = a;
+	});
+
+	lua_cb!(divmod(a: int, b: int) -> (int, int) {
+		(a / b, a % b)
+	});
+
+	lua_cb!(upto(n: int) -> lua::Variadic<lua::LuaValue> {
+		lua::Variadic(range(1, n + 1).map(|i| lua::Number(i as float)).collect())
+	});
+
+	lua.state().push_function(nothing);
+	lua.state().set_global("nothing");
+	lua.state().push_function(divmod);
+	lua.state().set_global("divmod");
+	lua.state().push_function(upto);
+	lua.state().set_global("upto");
+
+	lua.state().do_str("
+		assert(select('#', nothing(1)) == 0)
+		local q, r = divmod(17, 5)
+		assert(q == 3 and r == 2)
+		assert(select('#', upto(4)) == 4)
+		assert(select(4, upto(4)) == 4)
+		assert(select('#', upto(0)) == 0)
+	");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_callback_args() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	lua_cb!(greet(name: ~str, greeting: Option<~str>) -> ~str {
+		match greeting {
+			Some(g) => g + ", " + name,
+			None => ~"Hello, " + name
+		}
+	});
+
+	lua_cb!(scale(x: float; factor: float = 2.0, offset: float = 0.0) -> float {
+		x * factor + offset
+	});
+
+	lua_cb!(sum(first: int, rest: lua::Variadic<int>) -> int {
+		let lua::Variadic(rest) = rest;
+		rest.iter().fold(first, |a, b| a + *b)
+	});
+
+	lua.state().push_function(greet);
+	lua.state().set_global("greet");
+	lua.state().push_function(scale);
+	lua.state().set_global("scale");
+	lua.state().push_function(sum);
+	lua.state().set_global("sum");
+
+	lua.state().do_str("
+		assert(greet('Lua') == 'Hello, Lua')
+		assert(greet('Lua', 'Hi') == 'Hi, Lua')
+		assert(greet('Lua', nil) == 'Hello, Lua')
+		assert(scale(3) == 6)
+		assert(scale(3, 3) == 9)
+		assert(scale(3, nil, 1) == 7)
+		assert(sum(1) == 1)
+		assert(sum(1, 2, 3, 4) == 10)
+	");
+
+	match lua.state().try_do_str("sum(1, 2, 'x')") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("to 'sum' (number expected, got string)")); },
+		_ => { fail!("expected a runtime error") }
+	}
+
+	// Values that take no stack slots can't be collected
+	lua_cb!(nothing(rest: lua::Variadic<()>) -> int {
+		let lua::Variadic(rest) = rest;
+		rest.len() as int
+	});
+	lua.state().push_function(nothing);
+	lua.state().set_global("nothing");
+	assert!(lua.state().try_do_str("nothing(1, 2)").is_err());
+
+	fn pad(s: ~str, width: int, fill: ~str) -> ~str {
+		let mut s = s;
+		while (s.char_len() as int) < width { s.push_str(fill); }
+		s
+	}
+
+	struct Account {
+		balance: int
+	}
+
+	lua_method!(deposit(this: &mut Account; amount: int = 1) -> int {
+		this.balance += amount;
+		this.balance
+	});
+
+	impl lua::LuaUserData for Account {
+		fn lua_type_name(_: Option<Account>) -> &'static str { "test.Account" }
+
+		fn lua_methods(_: Option<Account>) -> ~[(&'static str, lua::LuaCallback)] {
+			~[("deposit", deposit)]
+		}
+	}
+
+	lua.module("text", |text| {
+		lua_def!(text::pad(s: ~str; width: int = 4, fill: ~str = ~".") -> ~str as c_pad);
+	});
+	lua.push_userdata(Account{ balance: 0 });
+	lua.state().set_global("account");
+
+	lua.state().do_str("
+		assert(text.pad('ab') == 'ab..')
+		assert(text.pad('ab', 3, '-') == 'ab-')
+		assert(text.pad('ab', nil, '*') == 'ab**')
+		assert(account:deposit() == 1)
+		assert(account:deposit(10) == 11)
+	");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_bool() {
+	lua_struct!(
+		Options:
+			name: ~str,
+			verbose: bool
+	);
+	lua_fn!( negate(b: bool) -> bool );
+
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.state().do_str("
+		function negate(b) return not b end
+		function check(o) return type(o.verbose) == 'boolean' and o.verbose end
+	");
+
+	assert!(negate(true, &lua) == false);
+	assert!(negate(false, &lua) == true);
+
+	lua.push(true);
+	match lua.state().index_type(-1) {
+		lua::TBoolean => {},
+		_ => { fail!("expected a boolean") }
+	}
+	assert!(lua.pop::<bool>());
+
+	lua.push(1);
+	assert!(lua.try_pop::<bool>().is_err());
+
+	let check: lua::LuaFunction = lua.global("check");
+	let opts = Options::Options{ name: ~"x", verbose: true };
+	assert!(check.call::<Options::Options, bool>(opts));
+
+	lua.push(Options::Options{ name: ~"y", verbose: false });
+	let opts: Options::Options = lua.pop();
+	assert!(opts.name == ~"y" && !opts.verbose);
+
+	lua_cb!(flag(b: lua::Truthy) -> bool {
+		*b
+	});
+	lua.state().push_function(flag);
+	lua.state().set_global("flag");
+	lua.state().do_str("
+		assert(flag(0) == true)
+		assert(flag('') == true)
+		assert(flag(false) == false)
+		assert(flag(nil) == false)
+		assert(flag() == false)
+	");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_numbers() {
+	let lua = lua::New();
+
+	lua.push(200u8);
+	assert!(lua.pop::<u8>() == 200u8);
+
+	lua.push(-5i32);
+	assert!(lua.pop::<i64>() == -5i64);
+
+	lua.push(4294967295u32);
+	assert!(lua.pop::<uint>() == 4294967295u);
+
+	lua.push(1.5f32);
+	assert!(lua.pop::<f32>() == 1.5f32);
+
+	// Integers beyond c_int survive the round trip
+	lua.push(5000000000i64);
+	assert!(lua.pop::<int>() == 5000000000i64 as int);
+
+	lua.push(256);
+	match lua.try_pop::<u8>() {
+		Err(lua::Runtime(err)) => { assert!(err.msg == ~"number out of range for u8"); },
+		_ => { fail!("expected a range error") }
+	}
+
+	lua.push(-1);
+	assert!(lua.try_pop::<uint>().is_err());
+
+	lua.push(2.5);
+	match lua.try_pop::<int>() {
+		Err(lua::Runtime(err)) => { assert!(err.msg == ~"number has no integer representation"); },
+		_ => { fail!("expected a conversion error") }
+	}
+
+	lua.push(1e300);
+	assert!(lua.try_pop::<f32>().is_err());
+
+	lua.push(300);
+	assert!(lua.pop::<lua::Saturating<u8>>() == lua::Saturating(255u8));
+	lua.push(-7.9);
+	assert!(lua.pop::<lua::Saturating<i8>>() == lua::Saturating(-7i8));
+	lua.push(-1);
+	assert!(lua.pop::<lua::Saturating<u32>>() == lua::Saturating(0u32));
+	lua.push(1e300);
+	assert!(lua.pop::<lua::Saturating<f32>>() == lua::Saturating(f32::max_value));
+
+	// Integers past 2^53 are rounded by push and refused when returned from callbacks
+	lua.push(9007199254740992u64);
+	assert!(lua.pop::<u64>() == 9007199254740992u64);
+	lua.push(9007199254740993u64);
+	assert!(lua.pop::<u64>() == 9007199254740992u64);
+	lua.push(lua::Saturating(9007199254740993i64));
+	assert!(lua.pop::<i64>() == 9007199254740992i64);
+
+	lua_cb!(offset(x: i64) -> i64 {
+		9007199254740990i64 + x
+	});
+	lua.state().push_function(offset);
+	lua.state().set_global("offset");
+	lua.state().do_str("assert(offset(2) == 2^53)");
+	match lua.state().try_do_str("offset(3)") {
+		Err(lua::Runtime(err)) => { assert!(err.msg.contains("can't be represented exactly")); },
+		_ => { fail!("expected a precision error") }
+	}
+
+	lua_cb!(pair() -> (i64, i64) {
+		(1, 9007199254740993i64)
+	});
+	lua_cb!(many() -> lua::Variadic<u64> {
+		lua::Variadic(~[1, 2, 9007199254740993u64])
+	});
+	lua_cb!(nested() -> Option<~[uint]> {
+		Some(~[9007199254740993u])
+	});
+	lua.state().push_function(pair);
+	lua.state().set_global("pair");
+	lua.state().push_function(many);
+	lua.state().set_global("many");
+	lua.state().push_function(nested);
+	lua.state().set_global("nested");
+	for code in ["pair()", "many()", "nested()"].iter() {
+		match lua.state().try_do_str(*code) {
+			Err(lua::Runtime(err)) => { assert!(err.msg.contains("can't be represented exactly")); },
+			_ => { fail!(fmt!("expected a precision error from %s", *code)) }
+		}
+	}
+
+	let t = lua.new_table();
+	assert!(t.try_set("big", 9007199254740993i64).is_err());
+	assert!(t.try_get::<Option<float>>("big").unwrap().is_none());
+
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_bytes() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	let data = ~[0x61u8, 0, 0x62, 0xff, 0];
+	lua.push(lua::Bytes(data.clone()));
+	lua.state().set_global("data");
+	lua.state().do_str("
+		assert(#data == 5)
+		assert(string.byte(data, 2) == 0 and string.byte(data, 4) == 255)
+	");
+
+	let back: lua::Bytes = lua.global("data");
+	assert!(back == lua::Bytes(data.clone()));
+
+	// Not UTF-8, so it can't be read as a ~str
+	match lua.try_global::<~str>("data") {
+		Err(lua::Runtime(err)) => { assert!(err.msg == ~"string is not valid UTF-8"); },
+		_ => { fail!("expected a UTF-8 error") }
+	}
+
+	// But it round-trips through LuaValue
+	let value: lua::LuaValue = lua.global("data");
+	match value {
+		lua::Binary(ref b) => { assert!(*b == data); },
+		_ => { fail!("expected a binary value") }
+	}
+	lua.push(value);
+	assert!(lua.state().index_str(-1) == ~"string: 5 bytes, not UTF-8");
+	assert!(lua.pop::<lua::Bytes>() == lua::Bytes(data.clone()));
+
+	lua.push("nul\x00inside");
+	assert!(lua.pop::<~str>() == ~"nul\x00inside");
+
+	lua.push("héllo");
+	lua.state().set_global("word");
+	lua.state().do_str("assert(#word == 6)");
+	assert!(lua.global::<~str>("word") == ~"héllo");
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_lua_str() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	lua_cb!(count_lines(text: lua::LuaStr) -> int {
+		let mut n = 1;
+		for b in text.as_bytes().iter() {
+			if *b == '\n' as u8 { n += 1; }
+		}
+		n
+	});
+
+	lua.state().push_function(count_lines);
+	lua.state().set_global("count_lines");
+	lua.state().do_str("
+		local lines = {}
+		for i = 1, 1000 do lines[i] = 'line ' .. i end
+		assert(count_lines(table.concat(lines, '\\n')) == 1000)
+	");
+
+	lua.push("borrowed");
+	let s: lua::LuaStr = lua.pop();
+	// The handle keeps the string alive after it left the stack
+	lua.state().do_str("collectgarbage()");
+	assert!(s.as_str() == "borrowed");
+	assert!(s.len() == 8);
+	assert!(s.as_bytes() == bytes!("borrowed"));
+
+	lua.push(lua::Bytes(~[0xffu8]));
+	assert!(lua.try_pop::<lua::LuaStr>().is_err());
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_option_conversions() {
+	lua_struct!(
+		Contact:
+			name: ~str,
+			email: Option<~str>
+	);
+	lua_fn!( find(name: &str) -> Option<~str> );
+	lua_fn!( describe(c: Contact::Contact) -> ~str );
+
+	let lua = lua::New();
+	lua.state().open_libs();
+	lua.state().do_str("
+		local phones = { alice = '555-1234' }
+		function find(name) return phones[name] end
+		function describe(c) return c.name .. ' <' .. (c.email or 'none') .. '>' end
+	");
+
+	assert!(find("alice", &lua) == Some(~"555-1234"));
+	assert!(find("bob", &lua) == None);
+
+	let c = Contact::Contact{ name: ~"bob", email: None };
+	assert!(describe(c, &lua) == ~"bob <none>");
+
+	lua.push(Contact::Contact{ name: ~"carol", email: Some(~"c@example.com") });
+	let c: Contact::Contact = lua.pop();
+	assert!(c.email == Some(~"c@example.com"));
+
+	lua.state().do_str("partial = { name = 'dave' }");
+	let c: Contact::Contact = lua.global("partial");
+	assert!(c.name == ~"dave" && c.email.is_none());
+
+	lua.push(Some(3));
+	lua.push(None::<int>);
+	assert!(lua.pop::<Option<int>>() == None);
+	assert!(lua.pop::<Option<int>>() == Some(3));
+	assert!(lua.state().get_top() == 0);
+}
+
+#[test]
+fn test_collections() {
+	let lua = lua::New();
+	lua.state().open_libs();
+
+	let mut tags = HashSet::new();
+	tags.insert(~"red");
+	tags.insert(~"blue");
+	lua.push(tags);
+	lua.state().set_global("tags");
+	lua.state().do_str("
+		assert(tags.red == true and tags.blue == true and tags.green == nil)
+		tags.green = true
+		tags.red = false
+	");
+	let tags: HashSet<~str> = lua.global("tags");
+	assert!(tags.len() == 2 && tags.contains(&~"green") && !tags.contains(&~"red"));
+
+	let ids: TreeSet<int> = {
+		lua.state().do_str("ids = { [3] = true, [1] = true, [2] = true }");
+		lua.global("ids")
+	};
+	assert!(ids.iter().map(|i| *i).collect::<~[int]>() == ~[1, 2, 3]);
+
+	let mut scores = TreeMap::new();
+	scores.insert(~"b", 2);
+	scores.insert(~"a", 1);
+	lua.push(scores);
+	lua.state().set_global("scores");
+	lua.state().do_str("assert(scores.a == 1 and scores.b == 2)");
+	let scores: TreeMap<~str, int> = lua.global("scores");
+	assert!(scores.iter().map(|(k, _)| k.clone()).collect::<~[~str]>() == ~[~"a", ~"b"]);
+
+	let mut queue = RingBuf::new();
+	queue.push_back(2);
+	queue.push_front(1);
+	lua.push(queue);
+	lua.state().set_global("queue");
+	lua.state().do_str("assert(#queue == 2 and queue[1] == 1 and queue[2] == 2)");
+	let queue: RingBuf<int> = lua.global("queue");
+	assert!(*queue.front().unwrap() == 1 && *queue.back().unwrap() == 2);
+
+	let pos = [1.0f32, 2.5, -3.0];
+	lua.push(pos);
+	lua.state().set_global("pos");
+	lua.state().do_str("assert(#pos == 3 and pos[2] == 2.5)");
+	let pos: [f32, ..3] = lua.global("pos");
+	assert!(pos == [1.0f32, 2.5, -3.0]);
+
+	lua.state().do_str("short = { 1, 2 }");
+	match lua.try_global::<[f32, ..3]>("short") {
+		Err(lua::Runtime(err)) => { assert!(err.msg == ~"table of 3 values expected, got 2"); },
+		_ => { fail!("expected a size error") }
+	}
+	assert!(lua.state().get_top() == 0);
+}